@@ -0,0 +1,285 @@
+use std::str::FromStr;
+
+use image::{ImageBuffer, Rgb};
+
+use crate::{
+  helper::{parse_numbers, Color, Vector2},
+  palette::Palette,
+};
+
+/// How the image is laid over the bowl when their shapes
+/// differ
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Fit {
+  /// Stretched to cover the bowl exactly
+  #[default]
+  Stretch,
+  /// Shown whole, leaving bars of the bowl uncovered
+  Contain,
+  /// Covers the bowl, cropping off what doesn't fit
+  Cover,
+}
+
+/// What circles outside the image get with [`Fit::Contain`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Letterbox {
+  Solid(Color),
+  /// Left out of the frames
+  Skip,
+}
+
+impl Default for Letterbox {
+  fn default() -> Self {
+    Self::Solid(Color(0, 0, 0))
+  }
+}
+
+impl FromStr for Letterbox {
+  type Err = String;
+
+  /// Parses `skip` or a hex color such as `#202020`
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    match spec {
+      "skip" => Ok(Self::Skip),
+      _ => Palette::parse_hex(spec).map(Self::Solid).map_err(|_| {
+        format!("unknown letterbox '{}', expected skip or a hex color", spec)
+      }),
+    }
+  }
+}
+
+/// Parses an alignment `x,y`, as fractions of the room left
+/// over along each axis
+pub fn parse_align(spec: &str) -> Result<(f32, f32), String> {
+  match parse_numbers(spec)?.as_slice() {
+    [x, y] if (0.0..=1.0).contains(x) && (0.0..=1.0).contains(y) => {
+      Ok((*x, *y))
+    },
+    _ => Err(format!(
+      "invalid alignment '{}', expected x,y between 0 and 1",
+      spec
+    )),
+  }
+}
+
+/// Where the image lies over the bowl
+pub struct Placement {
+  fit: Fit,
+  // Top left corner and size of the image, in the area
+  origin: Vector2,
+  size: Vector2,
+}
+
+impl Placement {
+  /// Lays an image `image` pixels in size over `bounds`.
+  /// `align` puts the image within the bounds with
+  /// [`Fit::Contain`], and picks the part that is kept with
+  /// [`Fit::Cover`]. 0.5 centers it
+  pub fn new(
+    fit: Fit,
+    align: (f32, f32),
+    bounds: (Vector2, Vector2),
+    image: (u32, u32),
+  ) -> Self {
+    let area = bounds.1 - bounds.0;
+    let (w, h) = (image.0 as f32, image.1 as f32);
+    let size = match fit {
+      Fit::Stretch => area,
+      Fit::Contain => Vector2::new(w, h) * (area.x / w).min(area.y / h),
+      Fit::Cover => Vector2::new(w, h) * (area.x / w).max(area.y / h),
+    };
+    let room = area - size;
+    Self {
+      fit,
+      origin: bounds.0 + Vector2::new(room.x * align.0, room.y * align.1),
+      size,
+    }
+  }
+
+  /// Where `pos` falls on the image, as fractions of its
+  /// size, or `None` when it's outside with
+  /// [`Fit::Contain`]
+  pub fn coords(&self, pos: Vector2) -> Option<(f32, f32)> {
+    let pos = pos - self.origin;
+    let (u, v) = (pos.x / self.size.x, pos.y / self.size.y);
+    let inside = (0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v);
+    if self.fit == Fit::Contain && !inside {
+      return None;
+    }
+    Some((u.clamp(0.0, 1.0), v.clamp(0.0, 1.0)))
+  }
+
+  /// Image pixels per pixel of the area along each axis,
+  /// for an image `image` pixels in size
+  pub fn scale(&self, image: (u32, u32)) -> (f32, f32) {
+    (image.0 as f32 / self.size.x, image.1 as f32 / self.size.y)
+  }
+}
+
+/// How a circle takes its color from the pixels under it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Sampling {
+  /// The pixel at the center
+  #[default]
+  Center,
+  /// Average of every pixel under the circle
+  DiskAverage,
+  /// Middle value of each channel under the circle, which
+  /// ignores stray pixels
+  Median,
+  /// Average under the circle weighted towards the center
+  Gaussian,
+}
+
+/// Picks colors from an image. Footprints are given in
+/// image pixels
+pub struct Sampler<'a> {
+  image: &'a ImageBuffer<Rgb<u8>, Vec<u8>>,
+  sampling: Sampling,
+  // Average in linear light rather than on the stored
+  // values
+  linear: bool,
+  to_linear: [f32; 256],
+}
+
+impl<'a> Sampler<'a> {
+  pub fn new(
+    image: &'a ImageBuffer<Rgb<u8>, Vec<u8>>,
+    sampling: Sampling,
+    linear: bool,
+  ) -> Self {
+    let mut to_linear = [0.0; 256];
+    for (i, v) in to_linear.iter_mut().enumerate() {
+      *v = srgb_to_linear(i as f32 / 255.0);
+    }
+    Self {
+      image,
+      sampling,
+      linear,
+      to_linear,
+    }
+  }
+
+  /// Color of a circle at `center` with radii `radius`
+  /// along each axis, in pixels
+  pub fn sample(&self, center: (f32, f32), radius: (f32, f32)) -> Color {
+    let (width, height) = self.image.dimensions();
+    let nearest = || {
+      let x = center.0.round().clamp(0.0, width as f32 - 1.0) as u32;
+      let y = center.1.round().clamp(0.0, height as f32 - 1.0) as u32;
+      let pixel = self.image.get_pixel(x, y);
+      Color(pixel[0], pixel[1], pixel[2])
+    };
+    if self.sampling == Sampling::Center {
+      return nearest();
+    }
+    // Pixels whose centers fall in the footprint, with how
+    // far out they are as a fraction of the radius
+    let (rx, ry) = (radius.0.max(0.5), radius.1.max(0.5));
+    let x_range = (center.0 - rx).ceil().max(0.0) as u32
+      ..((center.0 + rx).floor() + 1.0).clamp(0.0, width as f32) as u32;
+    let y_range = (center.1 - ry).ceil().max(0.0) as u32
+      ..((center.1 + ry).floor() + 1.0).clamp(0.0, height as f32) as u32;
+    let mut pixels = vec![];
+    for y in y_range {
+      for x in x_range.clone() {
+        let dx = (x as f32 - center.0) / rx;
+        let dy = (y as f32 - center.1) / ry;
+        let d2 = dx * dx + dy * dy;
+        if d2 <= 1.0 {
+          pixels.push((*self.image.get_pixel(x, y), d2));
+        }
+      }
+    }
+    if pixels.is_empty() {
+      return nearest();
+    }
+    match self.sampling {
+      Sampling::Center => unreachable!(),
+      Sampling::Median => {
+        let mut channels = [vec![], vec![], vec![]];
+        for (pixel, _) in &pixels {
+          for (channel, value) in channels.iter_mut().zip(pixel.0) {
+            channel.push(value);
+          }
+        }
+        let [r, g, b] = channels.map(|mut c| {
+          let middle = c.len() / 2;
+          *c.select_nth_unstable(middle).1
+        });
+        Color(r, g, b)
+      },
+      Sampling::DiskAverage => {
+        self.average(pixels.iter().map(|(p, _)| (p, 1.0)))
+      },
+      Sampling::Gaussian => {
+        // Half the radius as the standard deviation
+        let weight = |d2: f32| (-2.0 * d2).exp();
+        self.average(pixels.iter().map(|(p, d2)| (p, weight(*d2))))
+      },
+    }
+  }
+
+  // Weighted mean of the pixels
+  fn average<'p>(
+    &self,
+    pixels: impl Iterator<Item = (&'p Rgb<u8>, f32)>,
+  ) -> Color {
+    let mut sum = [0.0; 3];
+    let mut total = 0.0;
+    for (pixel, weight) in pixels {
+      for (s, v) in sum.iter_mut().zip(pixel.0) {
+        *s += weight
+          * match self.linear {
+            true => self.to_linear[v as usize],
+            false => v as f32 / 255.0,
+          };
+      }
+      total += weight;
+    }
+    let [r, g, b] = sum.map(|s| {
+      let v = s / total.max(f32::EPSILON);
+      let v = if self.linear { linear_to_srgb(v) } else { v };
+      (v * 255.0).round().clamp(0.0, 255.0) as u8
+    });
+    Color(r, g, b)
+  }
+}
+
+/// Converts an sRGB value between 0 and 1 to linear light
+pub fn srgb_to_linear(v: f32) -> f32 {
+  if v <= 0.04045 {
+    v / 12.92
+  } else {
+    ((v + 0.055) / 1.055).powf(2.4)
+  }
+}
+
+/// Converts a linear light value between 0 and 1 to sRGB
+pub fn linear_to_srgb(v: f32) -> f32 {
+  if v <= 0.0031308 {
+    v * 12.92
+  } else {
+    1.055 * v.powf(1.0 / 2.4) - 0.055
+  }
+}
+
+/// Converts a color to CIELAB, where distances roughly
+/// match how different colors look
+pub fn lab(color: Color) -> [f32; 3] {
+  let [r, g, b] =
+    [color.0, color.1, color.2].map(|v| srgb_to_linear(v as f32 / 255.0));
+  // Linear sRGB to XYZ, relative to the D65 white point
+  let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+  let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+  let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+  let f = |t: f32| {
+    if t > 0.008856 {
+      t.cbrt()
+    } else {
+      7.787 * t + 16.0 / 116.0
+    }
+  };
+  let (fx, fy, fz) = (f(x), f(y), f(z));
+  [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
@@ -0,0 +1,327 @@
+use std::{f32::consts::PI, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  draw::Overlay,
+  helper::{parse_numbers, Color, Vector2},
+};
+
+/// Shape the circles are kept inside of. Positions and
+/// sizes are fractions of the simulation area, so the same
+/// container works at any resolution. Lengths that aren't
+/// along an axis are fractions of the shorter side
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Container {
+  /// The whole simulation area
+  #[default]
+  Rect,
+  /// A round bowl
+  Circle { center: (f32, f32), radius: f32 },
+  /// The whole area with rounded corners
+  RoundedRect { corner: f32 },
+  /// Any simple polygon, convex or not
+  Polygon { points: Vec<(f32, f32)> },
+}
+
+#[inline]
+pub(crate) fn length(v: Vector2) -> f32 {
+  v.length2().sqrt()
+}
+
+// Signed distance and outward normal for an axis aligned
+// box centered on `center`
+pub(crate) fn box_distance(
+  p: Vector2,
+  center: Vector2,
+  half: Vector2,
+) -> (f32, Vector2) {
+  let local = p - center;
+  let q = Vector2::new(local.x.abs() - half.x, local.y.abs() - half.y);
+  let sign = Vector2::new(local.x.signum(), local.y.signum());
+  if q.x > 0.0 || q.y > 0.0 {
+    let outside = Vector2::new(q.x.max(0.0), q.y.max(0.0));
+    let d = length(outside);
+    let n = outside * (1.0 / d);
+    (d, Vector2::new(n.x * sign.x, n.y * sign.y))
+  } else if q.x > q.y {
+    (q.x, Vector2::new(sign.x, 0.0))
+  } else {
+    (q.y, Vector2::new(0.0, sign.y))
+  }
+}
+
+impl Container {
+  fn scale(area: (f32, f32), point: (f32, f32)) -> Vector2 {
+    Vector2::new(point.0 * area.0, point.1 * area.1)
+  }
+
+  /// Signed distance from `p` to the edge, negative inside,
+  /// together with the outward normal of the nearest edge
+  pub fn distance(&self, area: (f32, f32), p: Vector2) -> (f32, Vector2) {
+    let short = area.0.min(area.1);
+    let center = Vector2::new(area.0 / 2.0, area.1 / 2.0);
+    match self {
+      Self::Rect => box_distance(p, center, center),
+      Self::Circle { center, radius } => {
+        let center = Self::scale(area, *center);
+        let offset = p - center;
+        let d = length(offset);
+        let normal = if d > 0.0 {
+          offset * (1.0 / d)
+        } else {
+          Vector2::new(0.0, 1.0)
+        };
+        (d - radius * short, normal)
+      },
+      Self::RoundedRect { corner } => {
+        let corner = (corner * short).min(short / 2.0);
+        let half = center + -corner;
+        let (d, normal) = box_distance(p, center, half);
+        (d - corner, normal)
+      },
+      Self::Polygon { points } => {
+        let mut best = f32::MAX;
+        let mut closest = p;
+        let mut edge_normal = Vector2::new(0.0, 1.0);
+        let mut inside = false;
+        for i in 0..points.len() {
+          let a = Self::scale(area, points[i]);
+          let b = Self::scale(area, points[(i + 1) % points.len()]);
+          let edge = b - a;
+          let len2 = edge.length2();
+          let t = if len2 > 0.0 {
+            (((p.x - a.x) * edge.x + (p.y - a.y) * edge.y) / len2)
+              .clamp(0.0, 1.0)
+          } else {
+            0.0
+          };
+          let q = a + edge * t;
+          let d2 = (p - q).length2();
+          if d2 < best {
+            best = d2;
+            closest = q;
+            edge_normal = Vector2::new(edge.y, -edge.x) * (1.0 / len2.sqrt());
+          }
+          // Even-odd rule
+          if (a.y > p.y) != (b.y > p.y)
+            && p.x < a.x + (p.y - a.y) / (b.y - a.y) * edge.x
+          {
+            inside = !inside;
+          }
+        }
+        let d = best.sqrt();
+        let sign = if inside { -1.0 } else { 1.0 };
+        let normal = if d > 0.0 {
+          (p - closest) * (sign / d)
+        } else {
+          edge_normal
+        };
+        (d * sign, normal)
+      },
+    }
+  }
+
+  /// Checks the container has room inside, describing the
+  /// problem if not
+  pub fn validate(&self) -> Result<(), String> {
+    match self {
+      Self::Rect => {},
+      Self::Circle { center, radius } => {
+        if !(*radius > 0.0 && center.0.is_finite() && center.1.is_finite()) {
+          return Err(format!(
+            "Invalid circle container, the radius {} must be above 0",
+            radius
+          ));
+        }
+      },
+      Self::RoundedRect { corner } => {
+        if !(0.0..=0.5).contains(corner) {
+          return Err(format!(
+            "Invalid rounded container, the corner {} must be between 0 and \
+             0.5 inclusive",
+            corner
+          ));
+        }
+      },
+      Self::Polygon { points } => {
+        if points.len() < 3 {
+          return Err("A polygon container needs at least 3 points".into());
+        }
+        for (i, p) in points.iter().enumerate() {
+          let next = points[(i + 1) % points.len()];
+          if !(p.0.is_finite() && p.1.is_finite()) || *p == next {
+            return Err(format!(
+              "Invalid polygon container, point {} is repeated or not a \
+               number",
+              i + 1
+            ));
+          }
+        }
+        if self.area((1.0, 1.0)) < 1e-6 {
+          return Err("Invalid polygon container, it has no area".into());
+        }
+      },
+    }
+    Ok(())
+  }
+
+  pub fn contains(&self, area: (f32, f32), p: Vector2) -> bool {
+    self.distance(area, p).0 < 0.0
+  }
+
+  /// Moves a circle at `p` back inside, along the normal of
+  /// the nearest edge
+  #[inline]
+  pub fn constrain(
+    &self,
+    area: (f32, f32),
+    p: Vector2,
+    radius: f32,
+  ) -> Vector2 {
+    if let Self::Rect = self {
+      return Vector2::new(
+        p.x.clamp(radius, area.0 - radius),
+        p.y.clamp(radius, area.1 - radius),
+      );
+    }
+    let (d, normal) = self.distance(area, p);
+    if d > -radius {
+      p - normal * (d + radius)
+    } else {
+      p
+    }
+  }
+
+  /// Area inside the container
+  pub fn area(&self, area: (f32, f32)) -> f32 {
+    let short = area.0.min(area.1);
+    match self {
+      Self::Rect => area.0 * area.1,
+      Self::Circle { radius, .. } => PI * (radius * short).powi(2),
+      Self::RoundedRect { corner } => {
+        let corner = (corner * short).min(short / 2.0);
+        area.0 * area.1 - (4.0 - PI) * corner.powi(2)
+      },
+      Self::Polygon { points } => {
+        let points: Vec<Vector2> =
+          points.iter().map(|p| Self::scale(area, *p)).collect();
+        let twice: f32 = (0..points.len())
+          .map(|i| {
+            let a = points[i];
+            let b = points[(i + 1) % points.len()];
+            a.x * b.y - b.x * a.y
+          })
+          .sum();
+        twice.abs() / 2.0
+      },
+    }
+  }
+
+  /// Bounding box of the container, as the top left and
+  /// bottom right corners
+  pub fn bounds(&self, area: (f32, f32)) -> (Vector2, Vector2) {
+    let short = area.0.min(area.1);
+    match self {
+      Self::Rect | Self::RoundedRect { .. } => {
+        (Vector2::new(0.0, 0.0), Vector2::new(area.0, area.1))
+      },
+      Self::Circle { center, radius } => {
+        let center = Self::scale(area, *center);
+        let radius = radius * short;
+        (center + -radius, center + radius)
+      },
+      Self::Polygon { points } => points.iter().fold(
+        (
+          Vector2::new(f32::MAX, f32::MAX),
+          Vector2::new(f32::MIN, f32::MIN),
+        ),
+        |(min, max), p| {
+          let p = Self::scale(area, *p);
+          (
+            Vector2::new(min.x.min(p.x), min.y.min(p.y)),
+            Vector2::new(max.x.max(p.x), max.y.max(p.y)),
+          )
+        },
+      ),
+    }
+  }
+
+  /// Draws the container onto an overlay of `width` by
+  /// `height` pixels covering the simulation area. Outside
+  /// is filled with `background`, and the edge is traced in
+  /// `outline` when given
+  pub fn overlay(
+    &self,
+    area: (f32, f32),
+    width: u32,
+    height: u32,
+    background: Color,
+    outline: Option<Color>,
+  ) -> Overlay {
+    let mut overlay = Overlay::new(width, height);
+    let scale = (area.0 / width as f32, area.1 / height as f32);
+    let pixel = scale.0.max(scale.1);
+    for y in 0..height {
+      for x in 0..width {
+        let p =
+          Vector2::new((x as f32 + 0.5) * scale.0, (y as f32 + 0.5) * scale.1);
+        let d = self.distance(area, p).0 / pixel;
+        if let Some(color) = outline {
+          let alpha = (1.5 - d.abs()).clamp(0.0, 1.0);
+          if alpha > 0.0 {
+            overlay.set(x, y, color, alpha);
+            continue;
+          }
+        }
+        let alpha = (d + 0.5).clamp(0.0, 1.0);
+        if alpha > 0.0 {
+          overlay.set(x, y, background, alpha);
+        }
+      }
+    }
+    overlay
+  }
+}
+
+impl FromStr for Container {
+  type Err = String;
+
+  /// Parses `rect`, `circle[:x,y,radius]`,
+  /// `rounded[:corner]` or `polygon:x,y;x,y;...`
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    let (name, params) = spec.split_once(':').unwrap_or((spec, ""));
+    let values = parse_numbers(params)?;
+    let container = match (name, values.as_slice()) {
+      ("rect", []) => Self::Rect,
+      ("circle", []) => Self::Circle {
+        center: (0.5, 0.5),
+        radius: 0.5,
+      },
+      ("circle", [x, y, radius]) => Self::Circle {
+        center: (*x, *y),
+        radius: *radius,
+      },
+      ("rounded", []) => Self::RoundedRect { corner: 0.15 },
+      ("rounded", [corner]) => Self::RoundedRect { corner: *corner },
+      ("polygon", values) if values.len() >= 6 && values.len() % 2 == 0 => {
+        Self::Polygon {
+          points: values.chunks(2).map(|p| (p[0], p[1])).collect(),
+        }
+      },
+      ("rect" | "circle" | "rounded" | "polygon", _) => {
+        return Err(format!("wrong number of values for {}", name))
+      },
+      _ => {
+        return Err(format!(
+          "unknown container '{}', expected one of rect, circle, rounded or \
+           polygon",
+          name
+        ))
+      },
+    };
+    container.validate()?;
+    Ok(container)
+  }
+}
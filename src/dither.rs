@@ -0,0 +1,135 @@
+use crate::{
+  grid::Grid,
+  helper::{Color, Vector2},
+  palette::Palette,
+};
+
+// Circles count as neighbours up to this many times the
+// sum of their radii apart
+const REACH: f32 = 1.25;
+// Floyd-Steinberg weights by direction, in degrees with 0
+// pointing right and 90 down. Neighbours are only ever to
+// the right or below, since the ones before have been done
+const KERNEL: [(f32, f32); 5] = [
+  (0.0, 7.0),
+  (45.0, 1.0),
+  (90.0, 5.0),
+  (135.0, 3.0),
+  (180.0, 3.0),
+];
+
+/// A circle to dither: its spawn index, position and radius
+pub type Site = (usize, Vector2, f32);
+
+/// Snaps `colors`, indexed by spawn index, to `palette`
+/// while spreading each circle's error onto the neighbours
+/// that come after it, going top to bottom. `strength`
+/// scales how much of the error is passed on, where 0 is
+/// plain snapping
+pub fn dither(
+  palette: &Palette,
+  colors: &mut [Color],
+  sites: &[Site],
+  area: (f32, f32),
+  strength: f32,
+) {
+  let neighbours = neighbours(sites, area);
+  let mut order: Vec<usize> = (0..sites.len()).collect();
+  order.sort_by(|&a, &b| {
+    let (a, b) = (&sites[a], &sites[b]);
+    (a.1.y.total_cmp(&b.1.y))
+      .then(a.1.x.total_cmp(&b.1.x))
+      .then(a.0.cmp(&b.0))
+  });
+  let mut rank = vec![0; sites.len()];
+  for (i, &site) in order.iter().enumerate() {
+    rank[site] = i;
+  }
+  let mut error = vec![[0.0f32; 3]; sites.len()];
+  for &site in &order {
+    let (index, position, _) = sites[site];
+    let original = colors[index];
+    let e = error[site];
+    let wanted = [
+      original.0 as f32 + e[0],
+      original.1 as f32 + e[1],
+      original.2 as f32 + e[2],
+    ];
+    let [r, g, b] = wanted.map(|v| v.round().clamp(0.0, 255.0) as u8);
+    let chosen = palette.nearest(Color(r, g, b));
+    colors[index] = chosen;
+    // Clamped, so errors can't build up past what any
+    // color could make up for
+    let lost = [
+      r as f32 - chosen.0 as f32,
+      g as f32 - chosen.1 as f32,
+      b as f32 - chosen.2 as f32,
+    ];
+    let after: Vec<(usize, f32)> = neighbours[site]
+      .iter()
+      .filter(|&&other| rank[other] > rank[site])
+      .map(|&other| {
+        let offset = sites[other].1 - position;
+        let distance = offset.length2().sqrt().max(f32::EPSILON);
+        let angle = offset.y.atan2(offset.x).to_degrees().abs();
+        (other, kernel(angle) / distance)
+      })
+      .collect();
+    let total: f32 = after.iter().map(|(_, w)| w).sum();
+    if total <= 0.0 {
+      continue;
+    }
+    for (other, weight) in after {
+      let share = strength * weight / total;
+      for (e, l) in error[other].iter_mut().zip(lost) {
+        *e += l * share;
+      }
+    }
+  }
+}
+
+// Weight of the kernel towards `angle`, blending between
+// the nearest directions
+fn kernel(angle: f32) -> f32 {
+  for pair in KERNEL.windows(2) {
+    let ((a, wa), (b, wb)) = (pair[0], pair[1]);
+    if angle <= b {
+      let t = ((angle - a) / (b - a)).clamp(0.0, 1.0);
+      return wa + (wb - wa) * t;
+    }
+  }
+  KERNEL[KERNEL.len() - 1].1
+}
+
+// Sites close enough to each one to share its error, by
+// position in `sites`
+fn neighbours(sites: &[Site], area: (f32, f32)) -> Vec<Vec<usize>> {
+  let max_radius = sites.iter().map(|s| s.2).fold(0.5, f32::max);
+  // Positions of the sites in `sites`, reordered by the grid
+  let mut items: Vec<Site> = sites
+    .iter()
+    .enumerate()
+    .map(|(i, s)| (i, s.1, s.2))
+    .collect();
+  let mut grid = Grid::new();
+  grid.build(
+    &mut items,
+    &mut vec![],
+    max_radius * 2.0 * REACH,
+    area,
+    |s| s.1,
+  );
+  let mut neighbours = vec![vec![]; sites.len()];
+  for col in 0..grid.columns() {
+    let offset = grid.column_range(col..col + 2).start;
+    grid.column_pairs(col, |a, b| {
+      let (a, b) = (&items[a + offset], &items[b + offset]);
+      let reach = (a.2 + b.2) * REACH;
+      if (a.1 - b.1).length2() < reach * reach {
+        neighbours[a.0].push(b.0);
+        neighbours[b.0].push(a.0);
+      }
+    });
+  }
+  neighbours
+}
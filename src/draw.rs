@@ -3,6 +3,8 @@ use wgpu::{
   *,
 };
 
+use crate::helper::Color;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 struct Vertex {
@@ -73,6 +75,30 @@ struct GpuUniforms {
   height: f32,
 }
 
+/// Largest width or height that can be drawn, the default
+/// texture limit in wgpu
+pub const MAX_SIZE: u32 = 8192;
+
+// Bytes in a row of the output buffer. Copies out of a
+// texture need rows aligned to 256 bytes, so there may be
+// padding at the end
+fn padded_row(width: u32) -> u32 {
+  let bytes = std::mem::size_of::<u32>() as u32 * width;
+  bytes.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+}
+
+// Buffer the frame is copied into to read it back
+fn output_buffer(device: &Device, width: u32, height: u32) -> Buffer {
+  device.create_buffer(&wgpu::BufferDescriptor {
+    size: (padded_row(width) * height) as wgpu::BufferAddress,
+    usage: wgpu::BufferUsages::COPY_DST
+        // this tells wpgu that we want to read this buffer from the cpu
+        | wgpu::BufferUsages::MAP_READ,
+    label: None,
+    mapped_at_creation: true,
+  })
+}
+
 const SQUARE: &[Vertex] = &[
   Vertex {
     position: [-1.0, 1.0],
@@ -100,6 +126,68 @@ const SQUARE: &[Vertex] = &[
   },
 ];
 
+/// Fixed image composited over every frame, for things
+/// that don't move such as the container outline
+#[derive(Clone)]
+pub struct Overlay {
+  width: u32,
+  height: u32,
+  // Straight alpha RGBA
+  pixels: Vec<[u8; 4]>,
+}
+
+impl Overlay {
+  pub fn new(width: u32, height: u32) -> Self {
+    Self {
+      width,
+      height,
+      pixels: vec![[0; 4]; (width * height) as usize],
+    }
+  }
+
+  pub fn width(&self) -> u32 {
+    self.width
+  }
+
+  pub fn height(&self) -> u32 {
+    self.height
+  }
+
+  /// Blends `color` over the pixel at `x, y`
+  pub fn set(&mut self, x: u32, y: u32, color: Color, alpha: f32) {
+    let pixel = &mut self.pixels[(y * self.width + x) as usize];
+    let under = pixel[3] as f32 / 255.0;
+    let out = alpha + under * (1.0 - alpha);
+    if out <= 0.0 {
+      return;
+    }
+    let mix = |top: u8, bottom: u8| {
+      ((top as f32 * alpha + bottom as f32 * under * (1.0 - alpha)) / out)
+        .round() as u8
+    };
+    *pixel = [
+      mix(color.0, pixel[0]),
+      mix(color.1, pixel[1]),
+      mix(color.2, pixel[2]),
+      (out * 255.0).round() as u8,
+    ];
+  }
+
+  /// Composites the overlay onto a frame of RGBA bytes
+  pub fn apply(&self, bytes: &mut [u8]) {
+    for (pixel, over) in bytes.chunks_exact_mut(4).zip(self.pixels.iter()) {
+      let alpha = over[3] as u32;
+      if alpha == 0 {
+        continue;
+      }
+      for c in 0..3 {
+        pixel[c] = ((over[c] as u32 * alpha + pixel[c] as u32 * (255 - alpha))
+          / 255) as u8;
+      }
+    }
+  }
+}
+
 pub struct QuickDraw {
   device: Device,
   queue: Queue,
@@ -115,6 +203,7 @@ pub struct QuickDraw {
   output_buffer: Buffer,
   vertex_buffer: Buffer,
   pipeline: RenderPipeline,
+  overlay: Option<Overlay>,
 }
 
 impl QuickDraw {
@@ -207,16 +296,7 @@ impl QuickDraw {
       size: max_circles * std::mem::size_of::<Circle>() as u64,
     });
 
-    let u32_size = std::mem::size_of::<u32>() as u32;
-    let output_buffer_size = (u32_size * width * height) as wgpu::BufferAddress;
-    let output_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-      size: output_buffer_size,
-      usage: wgpu::BufferUsages::COPY_DST
-        // this tells wpgu that we want to read this buffer from the cpu
-        | wgpu::BufferUsages::MAP_READ,
-      label: None,
-      mapped_at_creation: true,
-    });
+    let output_buffer = output_buffer(&device, width, height);
     let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
       label: Some("Vertex Buffer"),
       usage: BufferUsages::VERTEX,
@@ -292,9 +372,16 @@ impl QuickDraw {
       output_buffer,
       vertex_buffer,
       pipeline,
+      overlay: None,
     }
   }
 
+  /// Sets an overlay drawn over every frame, or removes it.
+  /// It has to be the same size as the output
+  pub fn set_overlay(&mut self, overlay: Option<Overlay>) {
+    self.overlay = overlay;
+  }
+
   pub async fn resize(&mut self, width: u32, height: u32, max_circles: usize) {
     let texture_desc = wgpu::TextureDescriptor {
       size: wgpu::Extent3d {
@@ -314,16 +401,16 @@ impl QuickDraw {
     let texture = self.device.create_texture(&texture_desc);
     let texture_view = texture.create_view(&Default::default());
 
-    let u32_size = std::mem::size_of::<u32>() as u32;
-    let output_buffer_size = (u32_size * width * height) as wgpu::BufferAddress;
-    let output_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
-      size: output_buffer_size,
-      usage: wgpu::BufferUsages::COPY_DST
-        // this tells wpgu that we want to read this buffer from the cpu
-        | wgpu::BufferUsages::MAP_READ,
-      label: None,
-      mapped_at_creation: true,
-    });
+    let output_buffer = output_buffer(&self.device, width, height);
+    let uniforms = GpuUniforms {
+      width: width as f32,
+      height: height as f32,
+    };
+    self.queue.write_buffer(
+      &self.uniform_buffer,
+      0,
+      bytemuck::cast_slice(&[uniforms]),
+    );
     self.allocate(max_circles).await;
     self.width = width;
     self.height = height;
@@ -365,8 +452,6 @@ impl QuickDraw {
     render_pass.draw(0..6, 0..self.instance_count as u32);
     drop(render_pass);
 
-    let u32_size = std::mem::size_of::<u32>() as u32;
-
     encoder.copy_texture_to_buffer(
       wgpu::ImageCopyTexture {
         aspect: wgpu::TextureAspect::All,
@@ -378,7 +463,7 @@ impl QuickDraw {
         buffer: &self.output_buffer,
         layout: wgpu::ImageDataLayout {
           offset: 0,
-          bytes_per_row: Some(u32_size * self.width),
+          bytes_per_row: Some(padded_row(self.width)),
           rows_per_image: Some(self.height),
         },
       },
@@ -388,7 +473,7 @@ impl QuickDraw {
     self.queue.submit(Some(encoder.finish()));
   }
 
-  async fn read_output_buffer(&self) -> BufferView {
+  async fn read_output_buffer(&self) -> BufferView<'_> {
     let buffer_slice = self.output_buffer.slice(..);
 
     // NOTE: We have to create the mapping THEN device.poll()
@@ -423,21 +508,25 @@ impl QuickDraw {
     self.queue.write_buffer(
       &self.instance_buffer,
       0,
-      &bytemuck::cast_slice(&circles),
+      bytemuck::cast_slice(circles),
     );
     // self.queue.submit([]);
     self.instance_count = circles.len() as u64;
   }
 
   async fn bytes(&self) -> Vec<u8> {
-    let mut buffer = vec![0; self.output_buffer.size() as usize];
+    let row = std::mem::size_of::<u32>() * self.width as usize;
+    let mut buffer = Vec::with_capacity(row * self.height as usize);
     {
       let data = self.read_output_buffer().await;
-
-      for (i, d) in data.iter().enumerate() {
-        buffer[i] = *d;
+      // Leave out the padding at the end of each row
+      for padded in data.chunks_exact(padded_row(self.width) as usize) {
+        buffer.extend_from_slice(&padded[..row]);
       }
     }
+    if let Some(overlay) = &self.overlay {
+      overlay.apply(&mut buffer);
+    }
     buffer
   }
 
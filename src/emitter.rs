@@ -0,0 +1,538 @@
+use std::{collections::HashMap, f32::consts::PI, fmt::Debug};
+
+use serde::{Deserialize, Serialize};
+
+use crate::helper::Vector2;
+
+/// What an [`Emitter`] gets to know about the simulation
+pub struct EmitContext {
+  /// Steps since the simulation started
+  pub tick: usize,
+  /// Substeps since the start, offset by a phase picked
+  /// from the seed so each seed sweeps differently
+  pub clock: usize,
+  /// Size of the simulation area
+  pub area: (f32, f32),
+  /// Base radius of the circles
+  pub radius: f32,
+}
+
+/// A circle to be added, with its velocity in pixels per
+/// substep
+pub struct Spawn {
+  pub position: Vector2,
+  pub velocity: Vector2,
+}
+
+/// Source of new circles, driven once per step by
+/// [`Simulation::step`](crate::sim::Simulation::step).
+/// Emitters must only depend on the context they are given,
+/// otherwise the replay pass won't match preprocessing
+#[typetag::serde(tag = "type")]
+pub trait Emitter: EmitterClone + Debug + Send + Sync {
+  /// Pushes the circles to spawn this step onto `spawns`
+  fn emit(&self, ctx: &EmitContext, spawns: &mut Vec<Spawn>);
+
+  /// True when the emitter will never spawn again after
+  /// `tick`
+  fn finished(&self, _tick: usize) -> bool {
+    false
+  }
+}
+
+pub trait EmitterClone {
+  fn clone_box(&self) -> Box<dyn Emitter>;
+}
+
+impl<T: Emitter + Clone + 'static> EmitterClone for T {
+  fn clone_box(&self) -> Box<dyn Emitter> {
+    Box::new(self.clone())
+  }
+}
+
+impl Clone for Box<dyn Emitter> {
+  fn clone(&self) -> Self {
+    self.clone_box()
+  }
+}
+
+// Moves back and forth between 0 and 1 as the clock
+// advances
+#[inline]
+fn sweep(clock: usize) -> f32 {
+  let time = clock as f32 / (10.0 * PI);
+  0.5 - 0.5 * time.cos()
+}
+
+// Unit vector for an angle in degrees, 0 is to the right
+// and 90 is straight down
+#[inline]
+pub(crate) fn direction(degrees: f32) -> Vector2 {
+  let radians = degrees.to_radians();
+  Vector2::new(radians.cos(), radians.sin())
+}
+
+#[inline]
+fn lerp(range: (f32, f32), t: f32) -> f32 {
+  range.0 + (range.1 - range.0) * t
+}
+
+// How many circles to spawn on `tick` for a rate in
+// circles per step, spreading fractional rates evenly
+#[inline]
+fn count(rate: f32, tick: usize) -> usize {
+  let rate = rate.max(0.0) as f64;
+  ((rate * (tick + 1) as f64).floor() - (rate * tick as f64).floor()) as usize
+}
+
+// Spawn point for the `i`th circle of a step from a nozzle
+// firing along `direction`, each one a diameter further
+// along so they never start on top of each other
+#[inline]
+fn trail(
+  ctx: &EmitContext,
+  nozzle: Vector2,
+  direction: Vector2,
+  i: usize,
+) -> Vector2 {
+  inside(ctx, nozzle + direction * (ctx.radius * 2.0 * i as f32))
+}
+
+// Keeps a spawn point at least one radius inside the area
+#[inline]
+fn inside(ctx: &EmitContext, position: Vector2) -> Vector2 {
+  Vector2::new(
+    position.x.clamp(ctx.radius, ctx.area.0 - ctx.radius),
+    position.y.clamp(ctx.radius, ctx.area.1 - ctx.radius),
+  )
+}
+
+/// Two nozzles along the top, one per half, sweeping side
+/// to side. By default they fire along the arc the
+/// simulation always used, swinging from sideways to
+/// straight down and back
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TwinNozzle {
+  /// Height of the nozzles, as a fraction of the height.
+  /// Circles never spawn closer than a radius to the edge,
+  /// so 0 spawns them one radius down
+  pub y: f32,
+  /// Range the launch angle sweeps over instead, in
+  /// degrees
+  #[serde(default)]
+  pub angle: Option<(f32, f32)>,
+  pub speed: f32,
+  /// Circles per step from each nozzle
+  pub rate: f32,
+}
+
+impl Default for TwinNozzle {
+  fn default() -> Self {
+    Self {
+      y: 0.0,
+      angle: None,
+      speed: 1.0,
+      rate: 1.0,
+    }
+  }
+}
+
+#[typetag::serde(name = "twin")]
+impl Emitter for TwinNozzle {
+  fn emit(&self, ctx: &EmitContext, spawns: &mut Vec<Spawn>) {
+    let time = ctx.clock as f32 / (10.0 * PI);
+    let halfwidth = ctx.area.0 / 2.0;
+    let span = halfwidth - ctx.radius * 2.0;
+    let aim = match self.angle {
+      Some(angle) => direction(lerp(angle, sweep(ctx.clock))),
+      None => Vector2::new(time.cos(), time.sin().abs()),
+    };
+    let y = self.y * ctx.area.1;
+    let left = span * time.cos().abs() + ctx.radius;
+    let right = span * time.sin().abs() + ctx.radius + halfwidth;
+    for i in 0..count(self.rate, ctx.tick) {
+      for x in [left, right] {
+        spawns.push(Spawn {
+          position: trail(ctx, Vector2::new(x, y), aim, i),
+          velocity: aim * self.speed,
+        });
+      }
+    }
+  }
+}
+
+/// A single nozzle that sweeps over a range of angles
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Spout {
+  /// Position as a fraction of the area
+  pub position: (f32, f32),
+  /// Range the launch angle sweeps over, in degrees
+  pub angle: (f32, f32),
+  pub speed: f32,
+  /// Circles per step
+  pub rate: f32,
+}
+
+impl Default for Spout {
+  fn default() -> Self {
+    Self {
+      position: (0.5, 0.0),
+      angle: (45.0, 135.0),
+      speed: 2.0,
+      rate: 2.0,
+    }
+  }
+}
+
+#[typetag::serde(name = "spout")]
+impl Emitter for Spout {
+  fn emit(&self, ctx: &EmitContext, spawns: &mut Vec<Spawn>) {
+    let position =
+      Vector2::new(self.position.0 * ctx.area.0, self.position.1 * ctx.area.1);
+    let n = count(self.rate, ctx.tick);
+    for i in 0..n {
+      // Fan out circles spawned on the same step so they
+      // don't start on top of each other
+      let offset = (i as f32 - (n - 1) as f32 / 2.0) * ctx.radius * 2.0;
+      let angle = lerp(self.angle, sweep(ctx.clock + i * 7));
+      spawns.push(Spawn {
+        position: inside(ctx, position + Vector2::new(offset, 0.0)),
+        velocity: direction(angle) * self.speed,
+      });
+    }
+  }
+}
+
+/// Drops circles across the whole width
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rain {
+  /// Height the drops start at, as a fraction of the height
+  pub y: f32,
+  /// Range of fall angles, in degrees
+  pub angle: (f32, f32),
+  pub speed: f32,
+  /// Circles per step
+  pub rate: f32,
+}
+
+impl Default for Rain {
+  fn default() -> Self {
+    Self {
+      y: 0.0,
+      angle: (80.0, 100.0),
+      speed: 1.0,
+      rate: 2.0,
+    }
+  }
+}
+
+#[typetag::serde(name = "rain")]
+impl Emitter for Rain {
+  fn emit(&self, ctx: &EmitContext, spawns: &mut Vec<Spawn>) {
+    const GOLDEN: f64 = 0.618_033_988_749_895;
+    let n = count(self.rate, ctx.tick);
+    for i in 0..n {
+      // Low discrepancy sequence, spreads drops evenly
+      // without any randomness
+      let k = (ctx.clock * 4 + i) as f64;
+      let t = (k * GOLDEN).fract() as f32;
+      let x = ctx.radius + t * (ctx.area.0 - ctx.radius * 2.0);
+      let angle = lerp(self.angle, ((k * GOLDEN * GOLDEN).fract()) as f32);
+      spawns.push(Spawn {
+        position: inside(ctx, Vector2::new(x, self.y * ctx.area.1)),
+        velocity: direction(angle) * self.speed,
+      });
+    }
+  }
+}
+
+/// A pair of jets on the left and right walls, firing
+/// towards the middle
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SideJets {
+  /// Height of the jets, as a fraction of the height
+  pub y: f32,
+  /// Range of angles for the left jet in degrees. The right
+  /// jet is mirrored
+  pub angle: (f32, f32),
+  pub speed: f32,
+  /// Circles per step from each jet
+  pub rate: f32,
+}
+
+impl Default for SideJets {
+  fn default() -> Self {
+    Self {
+      y: 0.25,
+      angle: (-30.0, 10.0),
+      speed: 3.0,
+      rate: 1.0,
+    }
+  }
+}
+
+#[typetag::serde(name = "jets")]
+impl Emitter for SideJets {
+  fn emit(&self, ctx: &EmitContext, spawns: &mut Vec<Spawn>) {
+    let y = self.y * ctx.area.1;
+    let aim = direction(lerp(self.angle, sweep(ctx.clock)));
+    let mirrored = Vector2::new(-aim.x, aim.y);
+    for i in 0..count(self.rate, ctx.tick) {
+      spawns.push(Spawn {
+        position: trail(ctx, Vector2::new(0.0, y), aim, i),
+        velocity: aim * self.speed,
+      });
+      spawns.push(Spawn {
+        position: trail(ctx, Vector2::new(ctx.area.0, y), mirrored, i),
+        velocity: mirrored * self.speed,
+      });
+    }
+  }
+}
+
+/// Shoots circles upwards from the bottom
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Fountain {
+  /// Horizontal position, as a fraction of the width
+  pub x: f32,
+  /// Range the launch angle sweeps over, in degrees
+  pub angle: (f32, f32),
+  pub speed: f32,
+  /// Circles per step
+  pub rate: f32,
+}
+
+impl Default for Fountain {
+  fn default() -> Self {
+    Self {
+      x: 0.5,
+      angle: (250.0, 290.0),
+      speed: 5.0,
+      rate: 1.0,
+    }
+  }
+}
+
+#[typetag::serde(name = "fountain")]
+impl Emitter for Fountain {
+  fn emit(&self, ctx: &EmitContext, spawns: &mut Vec<Spawn>) {
+    let position = Vector2::new(self.x * ctx.area.0, ctx.area.1);
+    let aim = direction(lerp(self.angle, sweep(ctx.clock)));
+    for i in 0..count(self.rate, ctx.tick) {
+      spawns.push(Spawn {
+        position: trail(ctx, position, aim, i),
+        velocity: aim * self.speed,
+      });
+    }
+  }
+}
+
+/// Spawns a clump of circles all at once on one step
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Burst {
+  /// Step to spawn on
+  pub at: usize,
+  /// Number of circles to spawn
+  pub count: usize,
+  /// Center of the clump, as a fraction of the area
+  pub position: (f32, f32),
+  /// Range of launch angles, in degrees
+  pub angle: (f32, f32),
+  pub speed: f32,
+}
+
+impl Default for Burst {
+  fn default() -> Self {
+    Self {
+      at: 0,
+      count: 100,
+      position: (0.5, 0.25),
+      angle: (0.0, 360.0),
+      speed: 2.0,
+    }
+  }
+}
+
+#[typetag::serde(name = "burst")]
+impl Emitter for Burst {
+  fn emit(&self, ctx: &EmitContext, spawns: &mut Vec<Spawn>) {
+    const GOLDEN_ANGLE: f32 = 2.399_963;
+    if ctx.tick != self.at {
+      return;
+    }
+    let center =
+      Vector2::new(self.position.0 * ctx.area.0, self.position.1 * ctx.area.1);
+    // Sunflower layout, packs the clump without overlaps
+    let spacing = ctx.radius * 1.1;
+    for i in 0..self.count {
+      let angle = i as f32 * GOLDEN_ANGLE;
+      let offset =
+        Vector2::new(angle.cos(), angle.sin()) * (spacing * (i as f32).sqrt());
+      let t = (i as f32 + 0.5) / self.count as f32;
+      spawns.push(Spawn {
+        position: inside(ctx, center + offset),
+        velocity: direction(lerp(self.angle, t)) * self.speed,
+      });
+    }
+  }
+
+  fn finished(&self, tick: usize) -> bool {
+    tick >= self.at
+  }
+}
+
+// Splits "a..b" into a range, or a single value into an
+// empty one
+fn parse_range(value: &str) -> Result<(f32, f32), String> {
+  let parse = |v: &str| {
+    v.trim()
+      .parse::<f32>()
+      .map_err(|_| format!("invalid number '{}'", v))
+  };
+  match value.split_once("..") {
+    Some((a, b)) => Ok((parse(a)?, parse(b)?)),
+    None => parse(value).map(|v| (v, v)),
+  }
+}
+
+// The `key=value` pairs of a spec, removed as they are read
+// so leftovers can be reported
+pub(crate) struct Params<'a>(HashMap<&'a str, (f32, f32)>);
+
+impl<'a> Params<'a> {
+  pub(crate) fn parse(params: &'a str) -> Result<Self, String> {
+    let mut values = HashMap::new();
+    for pair in params.split(',').filter(|p| !p.is_empty()) {
+      let (key, value) = pair
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, found '{}'", pair))?;
+      values.insert(key.trim(), parse_range(value)?);
+    }
+    Ok(Self(values))
+  }
+
+  pub(crate) fn value(&mut self, key: &str, field: &mut f32) {
+    if let Some(v) = self.0.remove(key) {
+      *field = v.0;
+    }
+  }
+
+  pub(crate) fn count(&mut self, key: &str, field: &mut usize) {
+    if let Some(v) = self.0.remove(key) {
+      *field = v.0.max(0.0) as usize;
+    }
+  }
+
+  pub(crate) fn range(&mut self, key: &str, field: &mut (f32, f32)) {
+    if let Some(v) = self.0.remove(key) {
+      *field = v;
+    }
+  }
+
+  // Reads a rate, which has to be above 0 for the emitter
+  // to ever fill the bowl
+  fn rate(&mut self, field: &mut f32) -> Result<(), String> {
+    self.value("rate", field);
+    if field.is_nan() || *field <= 0.0 {
+      return Err(format!("the rate must be above 0, found {}", field));
+    }
+    Ok(())
+  }
+
+  pub(crate) fn optional(&mut self, key: &str, field: &mut Option<f32>) {
+    if let Some(v) = self.0.remove(key) {
+      *field = Some(v.0);
+    }
+  }
+
+  pub(crate) fn optional_range(
+    &mut self,
+    key: &str,
+    field: &mut Option<(f32, f32)>,
+  ) {
+    if let Some(v) = self.0.remove(key) {
+      *field = Some(v);
+    }
+  }
+
+  // Fails if any parameter wasn't read
+  pub(crate) fn finish(&self, name: &str) -> Result<(), String> {
+    match self.0.keys().next() {
+      Some(key) => Err(format!("unknown parameter '{}' for {}", key, name)),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Parses an emitter from the command line. The format is
+/// the emitter name, optionally followed by a colon and
+/// comma separated `key=value` pairs, e.g.
+/// `spout:x=0.3,angle=60..120,speed=2,rate=1`. Positions
+/// are fractions of the area and angles are in degrees,
+/// with 90 pointing down
+pub fn parse_emitter(spec: &str) -> Result<Box<dyn Emitter>, String> {
+  let (name, params) = spec.split_once(':').unwrap_or((spec, ""));
+  let mut p = Params::parse(params)?;
+  let emitter: Box<dyn Emitter> = match name {
+    "twin" => {
+      let mut e = TwinNozzle::default();
+      p.value("y", &mut e.y);
+      p.optional_range("angle", &mut e.angle);
+      p.value("speed", &mut e.speed);
+      p.rate(&mut e.rate)?;
+      Box::new(e)
+    },
+    "spout" => {
+      let mut e = Spout::default();
+      p.value("x", &mut e.position.0);
+      p.value("y", &mut e.position.1);
+      p.range("angle", &mut e.angle);
+      p.value("speed", &mut e.speed);
+      p.rate(&mut e.rate)?;
+      Box::new(e)
+    },
+    "rain" => {
+      let mut e = Rain::default();
+      p.value("y", &mut e.y);
+      p.range("angle", &mut e.angle);
+      p.value("speed", &mut e.speed);
+      p.rate(&mut e.rate)?;
+      Box::new(e)
+    },
+    "jets" => {
+      let mut e = SideJets::default();
+      p.value("y", &mut e.y);
+      p.range("angle", &mut e.angle);
+      p.value("speed", &mut e.speed);
+      p.rate(&mut e.rate)?;
+      Box::new(e)
+    },
+    "fountain" => {
+      let mut e = Fountain::default();
+      p.value("x", &mut e.x);
+      p.range("angle", &mut e.angle);
+      p.value("speed", &mut e.speed);
+      p.rate(&mut e.rate)?;
+      Box::new(e)
+    },
+    "burst" => {
+      let mut e = Burst::default();
+      p.count("at", &mut e.at);
+      p.count("count", &mut e.count);
+      p.value("x", &mut e.position.0);
+      p.value("y", &mut e.position.1);
+      p.range("angle", &mut e.angle);
+      p.value("speed", &mut e.speed);
+      Box::new(e)
+    },
+    _ => {
+      return Err(format!(
+        "unknown emitter '{}', expected one of twin, spout, rain, jets, \
+         fountain or burst",
+        name
+      ))
+    },
+  };
+  p.finish(name)?;
+  Ok(emitter)
+}
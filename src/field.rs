@@ -0,0 +1,291 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  container::length,
+  emitter::{direction, Params},
+  helper::Vector2,
+};
+
+/// Box between two corners, as fractions of the area
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Zone {
+  pub min: (f32, f32),
+  pub max: (f32, f32),
+}
+
+impl Zone {
+  fn contains(&self, area: (f32, f32), p: Vector2) -> bool {
+    let (x, y) = (p.x / area.0, p.y / area.1);
+    let (min, max) = (self.min, self.max);
+    (min.0.min(max.0)..=min.0.max(max.0)).contains(&x)
+      && (min.1.min(max.1)..=min.1.max(max.1)).contains(&y)
+  }
+}
+
+fn one() -> f32 {
+  1.0
+}
+
+fn enabled() -> bool {
+  true
+}
+
+/// Shape of a [`ForceField`]. Centers are fractions of the
+/// area and reaches are fractions of the shorter side
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FieldKind {
+  /// Pulls towards `center`. The pull fades to nothing at
+  /// `reach`, faster for a higher `falloff`, or is the same
+  /// everywhere without one. Makes round "planets" with a
+  /// circle container and no gravity
+  Attractor {
+    center: (f32, f32),
+    #[serde(default)]
+    reach: Option<f32>,
+    #[serde(default = "one")]
+    falloff: f32,
+  },
+  /// Pushes away from `center`, like an attractor
+  Repulsor {
+    center: (f32, f32),
+    #[serde(default)]
+    reach: Option<f32>,
+    #[serde(default = "one")]
+    falloff: f32,
+  },
+  /// Swirls clockwise around `center`, or anticlockwise for
+  /// a negative strength
+  Vortex {
+    center: (f32, f32),
+    #[serde(default)]
+    reach: Option<f32>,
+    #[serde(default = "one")]
+    falloff: f32,
+  },
+  /// Blows towards `angle`, in degrees with 90 pointing
+  /// down, everywhere or only inside `zone`
+  Wind {
+    angle: f32,
+    #[serde(default)]
+    zone: Option<Zone>,
+  },
+  /// Slows circles down, everywhere or only inside `zone`
+  Drag {
+    #[serde(default)]
+    zone: Option<Zone>,
+  },
+}
+
+/// Force applied to every circle alongside gravity, between
+/// `start` and `end` seconds. Strength is a multiple of the
+/// default gravity, the height of the area per second
+/// squared, except for drag where it is the fraction of
+/// speed lost per second
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ForceField {
+  #[serde(flatten)]
+  pub kind: FieldKind,
+  /// Lets the timeline turn the field on and off
+  #[serde(default)]
+  pub name: Option<String>,
+  /// Off until the timeline enables it when `false`
+  #[serde(default = "enabled")]
+  pub enabled: bool,
+  pub strength: f32,
+  #[serde(default)]
+  pub start: f32,
+  /// Lasts forever when `None`
+  #[serde(default)]
+  pub end: Option<f32>,
+}
+
+impl ForceField {
+  pub fn active(&self, time: f32) -> bool {
+    self.enabled && time >= self.start && self.end.is_none_or(|end| time < end)
+  }
+
+  /// Acceleration in pixels per second squared at `p`,
+  /// where `base` is the strength of default gravity,
+  /// together with the fraction of speed lost per second
+  #[inline]
+  pub fn force(
+    &self,
+    area: (f32, f32),
+    base: f32,
+    p: Vector2,
+  ) -> (Vector2, f32) {
+    let short = area.0.min(area.1);
+    // Unit offset from the center and how strongly the field
+    // acts at `p`
+    let radial = |center: (f32, f32), reach: Option<f32>, falloff: f32| {
+      let offset = p - Vector2::new(center.0 * area.0, center.1 * area.1);
+      let d = length(offset);
+      if d < 1e-3 {
+        return (Vector2::new(0.0, 0.0), 0.0);
+      }
+      let scale = match reach {
+        Some(reach) => (1.0 - d / (reach * short)).max(0.0).powf(falloff),
+        None => 1.0,
+      };
+      (offset * (1.0 / d), scale)
+    };
+    let pull = self.strength * base;
+    let none = Vector2::new(0.0, 0.0);
+    match &self.kind {
+      FieldKind::Attractor {
+        center,
+        reach,
+        falloff,
+      } => {
+        let (out, scale) = radial(*center, *reach, *falloff);
+        (out * (-pull * scale), 0.0)
+      },
+      FieldKind::Repulsor {
+        center,
+        reach,
+        falloff,
+      } => {
+        let (out, scale) = radial(*center, *reach, *falloff);
+        (out * (pull * scale), 0.0)
+      },
+      FieldKind::Vortex {
+        center,
+        reach,
+        falloff,
+      } => {
+        let (out, scale) = radial(*center, *reach, *falloff);
+        // Clockwise on screen, where y points down
+        (Vector2::new(-out.y, out.x) * (pull * scale), 0.0)
+      },
+      FieldKind::Wind { angle, zone } => {
+        match zone.as_ref().is_none_or(|z| z.contains(area, p)) {
+          true => (direction(*angle) * pull, 0.0),
+          false => (none, 0.0),
+        }
+      },
+      FieldKind::Drag { zone } => {
+        match zone.as_ref().is_none_or(|z| z.contains(area, p)) {
+          true => (none, self.strength),
+          false => (none, 0.0),
+        }
+      },
+    }
+  }
+
+  /// Checks the settings, describing the first problem
+  pub fn validate(&self) -> Result<(), String> {
+    if !self.strength.is_finite() {
+      return Err(format!("Invalid field strength {}", self.strength));
+    }
+    if let FieldKind::Attractor { reach, falloff, .. }
+    | FieldKind::Repulsor { reach, falloff, .. }
+    | FieldKind::Vortex { reach, falloff, .. } = &self.kind
+    {
+      if reach.is_some_and(|r| r <= 0.0) || *falloff < 0.0 {
+        return Err(
+          "Invalid field, the reach must be above 0 and the falloff not \
+           negative"
+            .into(),
+        );
+      }
+    }
+    if self.end.is_some_and(|end| end <= self.start) {
+      return Err(format!(
+        "Invalid field, it ends before it starts at {}s",
+        self.start
+      ));
+    }
+    Ok(())
+  }
+}
+
+/// Parses a force field from the command line, as the field
+/// name (attract, repel, vortex, wind or drag) followed by
+/// `key=value` pairs, e.g.
+/// `vortex:x=0.5,y=0.5,strength=2,reach=0.4,end=5`
+pub fn parse_field(spec: &str) -> Result<ForceField, String> {
+  let (name, params) = spec.split_once(':').unwrap_or((spec, ""));
+  let mut p = Params::parse(params)?;
+  let mut center = (0.5, 0.5);
+  let mut reach = None;
+  let mut falloff = 1.0;
+  let mut radial = |p: &mut Params| {
+    p.value("x", &mut center.0);
+    p.value("y", &mut center.1);
+    p.optional("reach", &mut reach);
+    p.value("falloff", &mut falloff);
+  };
+  // Zones are given as the corners x1,y1 and x2,y2
+  let zone = |p: &mut Params| {
+    let mut corners = [f32::NAN; 4];
+    for (key, value) in ["x1", "y1", "x2", "y2"].iter().zip(&mut corners) {
+      p.value(key, value);
+    }
+    match corners.iter().filter(|v| v.is_nan()).count() {
+      4 => Ok(None),
+      0 => Ok(Some(Zone {
+        min: (corners[0], corners[1]),
+        max: (corners[2], corners[3]),
+      })),
+      _ => Err("a zone needs all of x1, y1, x2 and y2".to_string()),
+    }
+  };
+  let kind = match name {
+    "attract" => {
+      radial(&mut p);
+      FieldKind::Attractor {
+        center,
+        reach,
+        falloff,
+      }
+    },
+    "repel" => {
+      radial(&mut p);
+      FieldKind::Repulsor {
+        center,
+        reach,
+        falloff,
+      }
+    },
+    "vortex" => {
+      radial(&mut p);
+      FieldKind::Vortex {
+        center,
+        reach,
+        falloff,
+      }
+    },
+    "wind" => {
+      let mut angle = 0.0;
+      p.value("angle", &mut angle);
+      FieldKind::Wind {
+        angle,
+        zone: zone(&mut p)?,
+      }
+    },
+    "drag" => FieldKind::Drag {
+      zone: zone(&mut p)?,
+    },
+    _ => {
+      return Err(format!(
+        "unknown field '{}', expected one of attract, repel, vortex, wind or \
+         drag",
+        name
+      ))
+    },
+  };
+  let mut field = ForceField {
+    kind,
+    name: None,
+    enabled: true,
+    strength: 1.0,
+    start: 0.0,
+    end: None,
+  };
+  p.value("strength", &mut field.strength);
+  p.value("start", &mut field.start);
+  p.optional("end", &mut field.end);
+  p.finish(name)?;
+  Ok(field)
+}
@@ -0,0 +1,182 @@
+use std::{f32::consts::PI, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  emitter::direction,
+  helper::{parse_numbers, Vector2},
+};
+
+/// Gravity at a point in time. Angles are in degrees with
+/// 90 pointing down, like emitters, and strength is a
+/// multiple of the normal gravity
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GravityKey {
+  /// Seconds since the start
+  pub time: f32,
+  pub angle: f32,
+  pub strength: f32,
+}
+
+impl Default for GravityKey {
+  fn default() -> Self {
+    Self {
+      time: 0.0,
+      angle: 90.0,
+      strength: 1.0,
+    }
+  }
+}
+
+/// Shakes the bowl back and forth along `angle`, adding to
+/// the gravity. The shaking eases in and out over
+/// `duration` seconds
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Shake {
+  pub start: f32,
+  pub duration: f32,
+  /// Peak pull, as a multiple of the normal gravity
+  pub strength: f32,
+  /// Shakes per second
+  pub frequency: f32,
+  pub angle: f32,
+}
+
+impl Default for Shake {
+  fn default() -> Self {
+    Self {
+      start: 0.0,
+      duration: 1.0,
+      strength: 1.0,
+      frequency: 3.0,
+      angle: 0.0,
+    }
+  }
+}
+
+/// Gravity over time, blending between keys. Straight down
+/// at normal strength when there are no keys
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GravityTrack {
+  pub keys: Vec<GravityKey>,
+  pub shakes: Vec<Shake>,
+}
+
+impl GravityTrack {
+  /// Gravity in pixels per second squared at `time`, where
+  /// `base` is the strength of normal gravity
+  pub fn at(&self, time: f32, base: f32) -> Vector2 {
+    let mut gravity = match self.blend(time) {
+      Some((angle, strength)) => direction(angle) * (strength * base),
+      // Exactly down, which rounding in the angle can't give
+      None => Vector2::new(0.0, base),
+    };
+    for shake in &self.shakes {
+      let t = time - shake.start;
+      if t < 0.0 || t > shake.duration {
+        continue;
+      }
+      let envelope = (PI * t / shake.duration.max(f32::EPSILON)).sin();
+      let swing = (2.0 * PI * shake.frequency * t).sin();
+      gravity +=
+        direction(shake.angle) * (swing * envelope * shake.strength * base);
+    }
+    gravity
+  }
+
+  // Angle and strength at `time`, holding the first and last
+  // keys outside of their range. `None` without keys
+  fn blend(&self, time: f32) -> Option<(f32, f32)> {
+    let first = self.keys.first()?;
+    let mut current = (first.angle, first.strength);
+    for pair in self.keys.windows(2) {
+      let (a, b) = (&pair[0], &pair[1]);
+      if time >= b.time {
+        current = (b.angle, b.strength);
+      } else if time > a.time {
+        let t = (time - a.time) / (b.time - a.time);
+        current = (
+          a.angle + (b.angle - a.angle) * t,
+          a.strength + (b.strength - a.strength) * t,
+        );
+      }
+    }
+    Some(current)
+  }
+
+  /// Seconds until the last change to gravity
+  pub fn end(&self) -> f32 {
+    let keys = self.keys.iter().map(|k| k.time);
+    let shakes = self.shakes.iter().map(|s| s.start + s.duration);
+    keys.chain(shakes).fold(0.0, f32::max)
+  }
+
+  /// Puts the keys in time order
+  pub fn sort(&mut self) {
+    self.keys.sort_by(|a, b| a.time.total_cmp(&b.time));
+  }
+}
+
+impl FromStr for GravityKey {
+  type Err = String;
+
+  /// Parses `seconds:angle[,strength]`
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    let (time, params) = spec
+      .split_once(':')
+      .ok_or_else(|| format!("expected seconds:angle, found '{}'", spec))?;
+    let time = parse_numbers(time)?;
+    match (time.as_slice(), parse_numbers(params)?.as_slice()) {
+      ([time], [angle]) => Ok(Self {
+        time: *time,
+        angle: *angle,
+        strength: 1.0,
+      }),
+      ([time], [angle, strength]) => Ok(Self {
+        time: *time,
+        angle: *angle,
+        strength: *strength,
+      }),
+      _ => Err(format!(
+        "expected seconds:angle[,strength], found '{}'",
+        spec
+      )),
+    }
+  }
+}
+
+impl FromStr for Shake {
+  type Err = String;
+
+  /// Parses `seconds:duration[,strength[,frequency]]`
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    let usage = || {
+      format!(
+        "expected seconds:duration[,strength[,frequency]], found '{}'",
+        spec
+      )
+    };
+    let (start, params) = spec.split_once(':').ok_or_else(usage)?;
+    let start = parse_numbers(start)?;
+    let params = parse_numbers(params)?;
+    let ([start], [duration, rest @ ..]) =
+      (start.as_slice(), params.as_slice())
+    else {
+      return Err(usage());
+    };
+    if rest.len() > 2 {
+      return Err(usage());
+    }
+    let defaults = Self::default();
+    Ok(Self {
+      start: *start,
+      duration: *duration,
+      strength: rest.first().copied().unwrap_or(defaults.strength),
+      frequency: rest.get(1).copied().unwrap_or(defaults.frequency),
+      ..defaults
+    })
+  }
+}
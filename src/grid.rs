@@ -0,0 +1,176 @@
+use crate::helper::Vector2;
+
+/// Uniform grid used as the collision broadphase. Items are
+/// reordered so that every cell is a contiguous range, with
+/// cells laid out column by column. Because of this the
+/// three cells to the right of any cell are also one
+/// contiguous range
+#[derive(Clone)]
+pub struct Grid {
+  cell_size: f32,
+  columns: usize,
+  rows: usize,
+  // Offset of the first item of each cell, plus a sentinel
+  starts: Vec<usize>,
+  keys: Vec<usize>,
+}
+
+impl Grid {
+  pub fn new() -> Self {
+    Self {
+      cell_size: 1.0,
+      columns: 1,
+      rows: 1,
+      starts: vec![0, 0],
+      keys: vec![],
+    }
+  }
+
+  pub fn columns(&self) -> usize {
+    self.columns
+  }
+
+  pub fn rows(&self) -> usize {
+    self.rows
+  }
+
+  pub fn cell_size(&self) -> f32 {
+    self.cell_size
+  }
+
+  /// Cell coordinates of a point, clamped to the grid
+  #[inline]
+  pub fn cell(&self, position: Vector2) -> (usize, usize) {
+    let col = (position.x / self.cell_size).max(0.0) as usize;
+    let row = (position.y / self.cell_size).max(0.0) as usize;
+    (col.min(self.columns - 1), row.min(self.rows - 1))
+  }
+
+  /// Range of items in the cells `col,
+  /// rows.start..rows.end`. Out of range rows are clipped
+  #[inline]
+  pub fn range(
+    &self,
+    col: usize,
+    rows: std::ops::Range<usize>,
+  ) -> std::ops::Range<usize> {
+    let base = col * self.rows;
+    let start = rows.start.min(self.rows);
+    let end = rows.end.min(self.rows);
+    self.starts[base + start]..self.starts[base + end]
+  }
+
+  /// Range of items in every cell of columns
+  /// `cols.start..cols.end`
+  #[inline]
+  pub fn column_range(
+    &self,
+    cols: std::ops::Range<usize>,
+  ) -> std::ops::Range<usize> {
+    let start = cols.start.min(self.columns) * self.rows;
+    let end = cols.end.min(self.columns) * self.rows;
+    self.starts[start]..self.starts[end]
+  }
+
+  /// Rebuilds the grid over an area of `size` with cells of
+  /// `cell_size`, reordering `items` with a stable counting
+  /// sort. Items outside the area are put in the nearest
+  /// cell
+  pub fn build<T: Copy>(
+    &mut self,
+    items: &mut Vec<T>,
+    scratch: &mut Vec<T>,
+    cell_size: f32,
+    size: (f32, f32),
+    position: impl Fn(&T) -> Vector2,
+  ) {
+    self.cell_size = cell_size.max(f32::EPSILON);
+    self.columns = ((size.0 / self.cell_size).ceil() as usize).max(1);
+    self.rows = ((size.1 / self.cell_size).ceil() as usize).max(1);
+    let cells = self.columns * self.rows;
+    self.starts.clear();
+    self.starts.resize(cells + 1, 0);
+    self.keys.clear();
+    for item in items.iter() {
+      let (col, row) = self.cell(position(item));
+      let key = col * self.rows + row;
+      self.keys.push(key);
+      self.starts[key + 1] += 1;
+    }
+    for i in 1..=cells {
+      self.starts[i] += self.starts[i - 1];
+    }
+    // Scatter into the scratch buffer, then swap it in
+    scratch.clear();
+    scratch.extend_from_slice(items);
+    let mut next = self.starts.clone();
+    for (item, &key) in items.iter().zip(self.keys.iter()) {
+      scratch[next[key]] = *item;
+      next[key] += 1;
+    }
+    std::mem::swap(items, scratch);
+  }
+
+  /// Calls `f` once for every pair of items in the same or
+  /// adjacent cells, for the cells in column `col`. The
+  /// indices passed are relative to `column_range(col..col
+  /// + 2).start`
+  #[inline]
+  pub fn column_pairs(&self, col: usize, mut f: impl FnMut(usize, usize)) {
+    // Items of a zero sized type take no memory
+    let mut items = vec![(); self.column_range(col..col + 2).len()];
+    self.active_pairs(col, &mut items, |_| true, |_, a, b| f(a, b));
+  }
+
+  /// Like [`Self::column_pairs`] over `items`, the items of
+  /// columns `col..col + 2`, but skips cells where neither
+  /// the cell nor the neighbours it pairs with hold an item
+  /// that is `active` when the cell comes up
+  #[inline]
+  pub fn active_pairs<T>(
+    &self,
+    col: usize,
+    items: &mut [T],
+    active: impl Fn(&T) -> bool,
+    mut f: impl FnMut(&mut [T], usize, usize),
+  ) {
+    let offset = self.column_range(col..col + 2).start;
+    let has_right = col + 1 < self.columns;
+    let any = |items: &[T], range: std::ops::Range<usize>| {
+      !range.is_empty()
+        && items[range.start - offset..range.end - offset]
+          .iter()
+          .any(&active)
+    };
+    for row in 0..self.rows {
+      let cell = self.range(col, row..row + 1);
+      // Same cell and the cell below, which follows it in
+      // memory
+      let below_end = self.range(col, row..row + 2).end;
+      let right = if has_right {
+        self.range(col + 1, row.saturating_sub(1)..row + 2)
+      } else {
+        0..0
+      };
+      if cell.is_empty()
+        || !(any(items, cell.start..below_end) || any(items, right.clone()))
+      {
+        continue;
+      }
+      for a in cell.clone() {
+        for b in a + 1..below_end {
+          f(items, a - offset, b - offset);
+        }
+        for b in right.clone() {
+          f(items, a - offset, b - offset);
+        }
+      }
+    }
+  }
+}
+
+impl Default for Grid {
+  fn default() -> Self {
+    Self::new()
+  }
+}
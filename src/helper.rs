@@ -1,9 +1,55 @@
 use std::ops::{Add, AddAssign, Mul, MulAssign, Sub, SubAssign};
 
-#[derive(Copy, Clone)]
+use rayon::ThreadPool;
+use serde::{Deserialize, Serialize};
+
+/// Starting value for [`fnv1a`]
+pub const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a hash. Unlike `DefaultHasher` this is stable
+/// across Rust releases and platforms, so it can feed
+/// anything that has to be reproducible. Chain calls by
+/// passing the previous hash back in
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+  bytes.iter().fold(hash, |hash, byte| {
+    (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+  })
+}
+
+/// Scrambles `n` into a well spread pseudo random number,
+/// using the SplitMix64 finalizer. Feeding it a counter
+/// gives a random sequence whose values can be picked in
+/// any order
+pub fn mix(n: u64) -> u64 {
+  let n = (n ^ (n >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+  let n = (n ^ (n >> 27)).wrapping_mul(0x94d049bb133111eb);
+  n ^ (n >> 31)
+}
+
+/// Pseudo random number between 0 and 1 for `n`, see
+/// [`mix`]
+pub fn unit(n: u64) -> f32 {
+  (mix(n) >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Parses a list of numbers separated by commas or
+/// semicolons, as used by the shape options on the command
+/// line
+pub fn parse_numbers(s: &str) -> Result<Vec<f32>, String> {
+  s.split([',', ';'])
+    .filter(|v| !v.trim().is_empty())
+    .map(|v| {
+      v.trim()
+        .parse::<f32>()
+        .map_err(|_| format!("invalid number '{}'", v))
+    })
+    .collect()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Color(pub u8, pub u8, pub u8);
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct Vector2 {
   pub x: f32,
   pub y: f32,
@@ -80,3 +126,35 @@ impl MulAssign<f32> for Vector2 {
     *self = *self * value;
   }
 }
+
+/// Runs `f` on every item, spread over up to `threads` of
+/// the threads in `pool`. Each thread gets a contiguous run
+/// of items in order, so as long as `f` only touches its
+/// own item the outcome does not depend on the thread count
+pub fn par_each<T: Send>(
+  pool: Option<&ThreadPool>,
+  threads: usize,
+  items: Vec<T>,
+  f: impl Fn(T) + Sync,
+) {
+  let Some(pool) = pool.filter(|_| threads > 1 && items.len() > 1) else {
+    items.into_iter().for_each(f);
+    return;
+  };
+  let chunk = items.len().div_ceil(threads);
+  let mut chunks = vec![];
+  let mut items = items.into_iter();
+  loop {
+    let next: Vec<T> = items.by_ref().take(chunk).collect();
+    if next.is_empty() {
+      break;
+    }
+    chunks.push(next);
+  }
+  let f = &f;
+  pool.scope(|scope| {
+    for chunk in chunks {
+      scope.spawn(move |_| chunk.into_iter().for_each(f));
+    }
+  });
+}
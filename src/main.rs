@@ -1,16 +1,32 @@
 #![feature(future_join)]
-use std::{cell::RefCell, future::join, io::Write, path::Path, sync::Arc};
+use std::{future::join, io::Write, path::Path};
 
-use draw::QuickDraw;
+use draw::{Overlay, QuickDraw};
 use gif::Frame;
 use image::{ImageBuffer, Rgb};
 use indicatif::{ProgressBar, ProgressStyle};
-use log::debug;
 
+pub mod color;
+pub mod container;
+pub mod dither;
 pub mod draw;
+pub mod emitter;
+pub mod field;
+pub mod gravity;
+pub mod grid;
 pub mod helper;
+pub mod obstacle;
+pub mod outro;
+pub mod palette;
+pub mod record;
+pub mod scene;
 pub mod sim;
+pub mod size;
+pub mod slideshow;
+pub mod state;
 pub mod tests;
+pub mod timeline;
+pub mod transition;
 
 pub fn make_progress(msg: &'static str, max: u64) -> ProgressBar {
   let progress = ProgressBar::new(max);
@@ -19,16 +35,362 @@ pub fn make_progress(msg: &'static str, max: u64) -> ProgressBar {
   progress
 }
 
-const WIDTH: f32 = 512.0;
-const HEIGHT: f32 = 512.0;
+// Width and height used when neither is given
+const SIZE: u32 = 512;
 
+/// Shape of the output, as width over height
+#[derive(Clone, Copy, Debug)]
+enum Aspect {
+  /// Same as the first input image
+  Auto,
+  Ratio(f32),
+}
+
+impl std::str::FromStr for Aspect {
+  type Err = String;
+
+  /// Parses `auto`, a ratio like `16:9` or a number like
+  /// `1.5`
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    if spec == "auto" {
+      return Ok(Self::Auto);
+    }
+    let parse = |v: &str| {
+      v.trim()
+        .parse::<f32>()
+        .ok()
+        .filter(|v| v.is_finite() && *v > 0.0)
+        .ok_or_else(|| format!("invalid aspect '{}', must be above 0", spec))
+    };
+    match spec.split_once(':') {
+      Some((w, h)) => Ok(Self::Ratio(parse(w)? / parse(h)?)),
+      None => parse(spec).map(Self::Ratio),
+    }
+  }
+}
+
+// Size of the output in pixels. A missing side follows
+// from the other one and the aspect, and without either the
+// longer side is `SIZE`
+fn output_size(
+  width: Option<u32>,
+  height: Option<u32>,
+  aspect: Option<f32>,
+) -> Result<(u32, u32), String> {
+  let side = |v: f32| (v.round() as u32).max(1);
+  let (width, height) = match (width, height, aspect) {
+    (Some(_), Some(_), Some(_)) => {
+      return Err(
+        "Only one of --width and --height can be given with --aspect".into(),
+      )
+    },
+    (Some(width), Some(height), None) => (width, height),
+    (Some(width), None, aspect) => {
+      (width, side(width as f32 / aspect.unwrap_or(1.0)))
+    },
+    (None, Some(height), aspect) => {
+      (side(height as f32 * aspect.unwrap_or(1.0)), height)
+    },
+    (None, None, None) => (SIZE, SIZE),
+    (None, None, Some(aspect)) if aspect >= 1.0 => {
+      (SIZE, side(SIZE as f32 / aspect))
+    },
+    (None, None, Some(aspect)) => (side(SIZE as f32 * aspect), SIZE),
+  };
+  let limits = 1..=draw::MAX_SIZE;
+  if !limits.contains(&width) || !limits.contains(&height) {
+    return Err(format!(
+      "Invalid output size {}x{}, each side must be between 1 and {}",
+      width,
+      height,
+      draw::MAX_SIZE
+    ));
+  }
+  Ok((width, height))
+}
+
+// Pixels in the frames drawn of `sim`, one per unit of its
+// area
+fn frame_size(sim: &Simulation) -> (u32, u32) {
+  let (width, height) = sim.area_size();
+  (width.round() as u32, height.round() as u32)
+}
+
+// Fills in the size field from the image when asked to
+fn auto_size(
+  image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+  config: &SimulationConfig,
+) -> SimulationConfig {
+  let mut config = config.clone();
+  if config.auto_size && config.size_field.is_none() {
+    config.size_field = Some(size::SizeField::from_detail(image));
+  }
+  config
+}
+
+/// Forms each image in turn, see
+/// [`Simulation::simulate_images`]. Automatic sizes come
+/// from the first image
 pub async fn preprocess(
-  image: ImageBuffer<Rgb<u8>, Vec<u8>>,
-  radius: f32,
-) -> (sim::Simulation, usize, usize) {
-  let (sim, it, max_circles) =
-    sim::Simulation::simulate_image(WIDTH, HEIGHT, radius, image).await;
-  (sim, it, max_circles)
+  images: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+  config: &SimulationConfig,
+) -> (sim::Simulation, usize, usize, Option<record::Recording>) {
+  let config = auto_size(&images[0], config);
+  sim::Simulation::simulate_images(&config, images).await
+}
+
+/// Checks the replay matches preprocessing after every
+/// step, see [`Simulation::verify`]
+pub async fn verify(
+  images: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+  config: &SimulationConfig,
+) -> Result<usize, sim::Divergence> {
+  let config = auto_size(&images[0], config);
+  sim::Simulation::verify(&config, images).await
+}
+
+/// Overlays drawn over the frames, switched when the
+/// obstacles are removed
+#[derive(Default)]
+pub struct Overlays {
+  pub base: Option<Overlay>,
+  pub obstacles: Option<Overlay>,
+}
+
+/// Overlays drawn over the frames and which one is shown,
+/// frames recorded while preprocessing to replay before
+/// simulating any further, and how circles color in
+pub struct Renderer {
+  overlays: Overlays,
+  obstacles_shown: Option<bool>,
+  recording: Option<record::Recording>,
+  transition: Option<transition::Transition>,
+  // Circles in the whole animation, for rainbow colors
+  count: usize,
+  // Seconds since the start the wave started at
+  wave: Option<f32>,
+}
+
+impl Renderer {
+  pub fn new(
+    overlays: Overlays,
+    recording: Option<record::Recording>,
+    transition: Option<transition::Transition>,
+  ) -> Self {
+    Self {
+      overlays,
+      obstacles_shown: None,
+      recording,
+      transition,
+      count: 0,
+      wave: None,
+    }
+  }
+
+  // Color of the circle with spawn index `index` at `time`,
+  // somewhere between the start color and `target`
+  fn color(
+    &self,
+    sim: &Simulation,
+    index: usize,
+    target: helper::Color,
+    height: f32,
+    rested: f32,
+    time: f32,
+  ) -> helper::Color {
+    let Some(transition) = &self.transition else {
+      return target;
+    };
+    transition.color(
+      target,
+      &transition::Moment {
+        index,
+        count: self.count,
+        born: sim.born(index),
+        rested,
+        height: height / sim.area_size().1,
+        time,
+        wave: self.wave.map(|start| time - start),
+      },
+    )
+  }
+
+  fn show_obstacles(&mut self, draw: &mut QuickDraw, obstacles: bool) {
+    let active = obstacles && self.overlays.obstacles.is_some();
+    if self.obstacles_shown != Some(active) {
+      self.obstacles_shown = Some(active);
+      let overlay = if active {
+        &self.overlays.obstacles
+      } else {
+        &self.overlays.base
+      };
+      draw.set_overlay(overlay.clone());
+    }
+  }
+
+  // Draws the current state while running the next `step`
+  // steps, returning RGBA bytes
+  async fn frame(
+    &mut self,
+    draw: &mut QuickDraw,
+    sim: &mut Simulation,
+    step: usize,
+  ) -> Vec<u8> {
+    self.show_obstacles(draw, sim.obstacles_active());
+    let time = sim.time();
+    let circles = sim
+      .circles
+      .iter()
+      .filter(|c| !sim.hidden_at(c.index(), time))
+      .map(|c| {
+        let target = sim.color_at(c.index(), time);
+        let color =
+          self.color(sim, c.index(), target, c.position.y, c.rested(), time);
+        draw::Circle {
+          position: [c.position.x, c.position.y],
+          radius: c.radius,
+          color: [color.0, color.1, color.2, 255],
+        }
+      })
+      .collect::<Vec<draw::Circle>>();
+    let bytes_future = draw.draw_circles(&circles);
+    let steps = sim.steps(step);
+    let bytes = join!(bytes_future, steps).await.0;
+    bytes
+  }
+
+  // Draws a recorded frame in the colors of `sim`
+  async fn replay(
+    &mut self,
+    draw: &mut QuickDraw,
+    sim: &Simulation,
+    frame: usize,
+  ) -> std::io::Result<Vec<u8>> {
+    let recording = self.recording.as_mut().unwrap();
+    let (samples, obstacles, time) = recording.frame(frame)?;
+    self.show_obstacles(draw, obstacles);
+    let circles = samples
+      .iter()
+      .filter(|c| !sim.hidden_at(c.index, time))
+      .map(|c| {
+        let target = sim.color_at(c.index, time);
+        let color =
+          self.color(sim, c.index, target, c.position.y, c.rested, time);
+        draw::Circle {
+          position: [c.position.x, c.position.y],
+          radius: c.radius,
+          color: [color.0, color.1, color.2, 255],
+        }
+      })
+      .collect::<Vec<draw::Circle>>();
+    Ok(draw.draw_circles(&circles).await)
+  }
+}
+
+// Frames blended in to get back to the first frame when
+// looping
+const LOOP_BLEND: usize = 8;
+
+/// Order the frames are played in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Direction {
+  #[default]
+  Forward,
+  /// Starts from the finished image and comes apart
+  Reverse,
+  /// Forward, then back again
+  Pingpong,
+}
+
+// Encodes frames, remembering the first and last ones.
+// Frames use the shared palette when given, or a palette of
+// their own
+#[derive(Default)]
+struct Frames {
+  frames: Vec<Frame<'static>>,
+  first: Option<Vec<u8>>,
+  last: Vec<u8>,
+  palette: Option<palette::GifPalette>,
+  size: (u16, u16),
+}
+
+impl Frames {
+  fn push(&mut self, bytes: Vec<u8>) {
+    self.first.get_or_insert_with(|| bytes.clone());
+    let (width, height) = self.size;
+    let frame = match &mut self.palette {
+      Some(palette) => Frame::from_indexed_pixels(
+        width,
+        height,
+        &palette.indices(&bytes),
+        None,
+      ),
+      None => Frame::from_rgba(width, height, &mut bytes.clone()),
+    };
+    self.frames.push(frame);
+    self.last = bytes;
+  }
+
+  // Puts the frames in playback order. The simulation
+  // itself can't run backwards, since emitters and walls
+  // lose information, so the recorded frames are replayed
+  // in reverse instead
+  fn arrange(&mut self, direction: Direction) {
+    match direction {
+      Direction::Forward => {},
+      Direction::Reverse => {
+        self.frames.reverse();
+        if let Some(first) = self.first.as_mut() {
+          std::mem::swap(first, &mut self.last);
+        }
+      },
+      Direction::Pingpong => {
+        // Leave out the turning points so they aren't shown
+        // twice
+        let count = self.frames.len();
+        let back: Vec<_> = self.frames[1.min(count)..count.saturating_sub(1)]
+          .iter()
+          .rev()
+          .cloned()
+          .collect();
+        self.frames.extend(back);
+        if let Some(first) = &self.first {
+          self.last = first.clone();
+        }
+      },
+    }
+  }
+
+  // Adds frames going from the last frame back to the first
+  fn blend_back(&mut self) {
+    if let Some(first) = self.first.clone() {
+      let end = self.last.clone();
+      for i in 1..=LOOP_BLEND {
+        self.push(blend(&end, &first, i as f32 / (LOOP_BLEND + 1) as f32));
+      }
+    }
+  }
+}
+
+// Mixes the colors of two RGBA frames, `t` of the way from
+// `a` to `b`. Pixels stay opaque, since transparent ones
+// would show the previous frame through
+fn blend(a: &[u8], b: &[u8], t: f32) -> Vec<u8> {
+  a.chunks_exact(4)
+    .zip(b.chunks_exact(4))
+    .flat_map(|(a, b)| {
+      let mix = |i: usize| {
+        (a[i] as f32 + (b[i] as f32 - a[i] as f32) * t).round() as u8
+      };
+      [mix(0), mix(1), mix(2), 255]
+    })
+    .collect()
+}
+
+// Fades an RGBA frame toward black
+fn darken(bytes: &[u8], brightness: f32) -> Vec<u8> {
+  let black = [0, 0, 0, 255].repeat(bytes.len() / 4);
+  blend(&black, bytes, brightness)
 }
 
 pub async fn simulate(
@@ -37,36 +399,99 @@ pub async fn simulate(
   it: usize,
   step: usize,
   max_circles: usize,
+  mut renderer: Renderer,
+  ending: &outro::Ending,
 ) -> Vec<Frame<'static>> {
-  draw.resize(WIDTH as u32, HEIGHT as u32, max_circles).await;
-  let mut frames = vec![];
+  let (width, height) = frame_size(&sim);
+  draw.resize(width, height, max_circles).await;
+  renderer.count = max_circles;
+  let mut frames = Frames {
+    palette: sim.palette().map(|p| palette::GifPalette::new(&p)),
+    size: (width as u16, height as u16),
+    ..Default::default()
+  };
+  // A recording already holds everything up to `it`
+  let recorded = renderer.recording.as_ref().map_or(0, |r| r.len());
+  let progress = make_progress("Replaying    ", recorded as u64);
+  for frame in 0..recorded {
+    match renderer.replay(draw, &sim, frame).await {
+      Ok(bytes) => frames.push(bytes),
+      Err(e) => {
+        eprintln!("Error reading recorded frames:");
+        eprintln!("{}", e);
+        std::process::exit(1);
+      },
+    }
+    progress.inc(1);
+  }
+  progress.finish();
   let progress =
     make_progress("Simulating   ", ((it - sim.clock) / sim.substeps) as u64);
   while sim.clock < it {
-    let circles = &sim
-      .circles
-      .iter()
-      .map(|c| draw::Circle {
-        position: [c.position.x, c.position.y],
-        radius: c.radius,
-        color: [c.color.0, c.color.1, c.color.2, 255],
-      })
-      .collect::<Vec<draw::Circle>>();
-    let bytes_future = draw.draw_circles(circles);
-    let steps = sim.steps(step);
-    let mut bytes = join!(bytes_future, steps).await.0;
-    let frame = gif::Frame::from_rgba(WIDTH as u16, HEIGHT as u16, &mut bytes);
-    frames.push(frame);
-    progress.inc(step as u64);
+    // Stop exactly where preprocessing did
+    let steps = step.min((it - sim.clock) / sim.substeps);
+    frames.push(renderer.frame(draw, &mut sim, steps).await);
+    progress.inc(steps as u64);
   }
   progress.finish();
-  frames
+  let checksum = sim.checksum();
+  if let Some(expected) = sim.expected_checksum().filter(|e| *e != checksum) {
+    eprintln!(
+      "The replay ended in a different state than preprocessing (checksum \
+       {:016x}, expected {:016x}), so the colors would be on the wrong \
+       circles. Run the `verify` command with the same options to find \
+       where it diverges, or use `--record` to skip the replay",
+      checksum, expected
+    );
+    std::process::exit(1);
+  }
+  // Keep going until the wave has passed over everything
+  if let Some(seconds) = renderer.transition.and_then(|t| t.wave_seconds()) {
+    let start = sim.time();
+    renderer.wave = Some(start);
+    while sim.time() - start < seconds {
+      frames.push(renderer.frame(draw, &mut sim, step).await);
+    }
+  }
+  // Stays at 0 once a fade has finished
+  let mut brightness = 1.0;
+  for outro in &ending.outros {
+    outro.start(&mut sim);
+    let start = sim.time();
+    while sim.time() - start < outro.seconds() && !outro.done(&sim) {
+      let bytes = renderer.frame(draw, &mut sim, step).await;
+      if let outro::Outro::Fade { seconds } = outro {
+        let faded = 1.0 - ((sim.time() - start) / seconds).min(1.0);
+        frames.push(darken(&bytes, brightness * faded));
+      } else {
+        frames.push(darken(&bytes, brightness));
+      }
+    }
+    if let outro::Outro::Fade { .. } = outro {
+      brightness = 0.0;
+    }
+  }
+  frames.arrange(ending.direction);
+  // Ping-pong already ends where it started
+  if ending.looping && ending.direction != Direction::Pingpong {
+    frames.blend_back();
+  }
+  frames.frames
 }
 
-pub async fn encode(frames: Vec<Frame<'static>>, repeat: bool) -> Vec<u8> {
+/// Writes `width` by `height` frames to a GIF, with
+/// `palette` as RGB bytes for frames that don't have their
+/// own
+pub async fn encode(
+  frames: Vec<Frame<'static>>,
+  (width, height): (u32, u32),
+  repeat: bool,
+  palette: &[u8],
+) -> Vec<u8> {
   let mut buffer = Vec::<u8>::new();
   let mut encoder =
-    gif::Encoder::new(&mut buffer, WIDTH as u16, HEIGHT as u16, &[]).unwrap();
+    gif::Encoder::new(&mut buffer, width as u16, height as u16, palette)
+      .unwrap();
   let progress = make_progress("Encoding     ", frames.len() as u64);
   for mut frame in frames {
     frame.delay = 1;
@@ -82,20 +507,55 @@ pub async fn encode(frames: Vec<Frame<'static>>, repeat: bool) -> Vec<u8> {
 }
 
 use clap::Parser;
-use sim::Simulation;
+use sim::{Simulation, SimulationConfig};
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+  /// Run preprocessing with the given options, then replay
+  /// it and compare the two after every step, reporting
+  /// the first step and circle where they differ. Options
+  /// go before the command, e.g.
+  /// `fishbowl -i image.png -j 4 verify`
+  Verify,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+  #[command(subcommand)]
+  command: Option<Command>,
+
   /// Input file to process. All common image types are
   /// supported, see the `image` crate docs for specific
-  /// compatibility
-  #[arg(short = 'i', value_hint = clap::ValueHint::DirPath)]
-  input: std::path::PathBuf,
+  /// compatibility. Can be repeated to form each image in
+  /// turn with the same circles, see `--change`
+  #[arg(
+    short = 'i',
+    value_hint = clap::ValueHint::DirPath,
+    required_unless_present = "load_state"
+  )]
+  input: Vec<std::path::PathBuf>,
 
   /// Output file path ('./output.gif' by default)
   #[arg(short = 'o', value_hint = clap::ValueHint::DirPath)]
   output: Option<std::path::PathBuf>,
 
+  /// Width of the output in pixels. Without `--height` it
+  /// follows `--aspect`, or is square
+  #[arg(long = "width")]
+  width: Option<u32>,
+
+  /// Height of the output in pixels. Without `--width` it
+  /// follows `--aspect`, or is square
+  #[arg(long = "height")]
+  height: Option<u32>,
+
+  /// Shape of the output: `auto` to match the first input
+  /// image, a ratio like `16:9` or a number like `1.5`.
+  /// The longer side is 512 pixels unless `--width` or
+  /// `--height` is given (512x512 by default)
+  #[arg(long = "aspect")]
+  aspect: Option<Aspect>,
+
   /// How many physics-steps between frames. Affects the
   /// speed and length of the animation
   #[arg(short = 's')]
@@ -107,12 +567,304 @@ struct Args {
   #[arg(short = 'r')]
   radius: Option<f32>,
 
+  /// Grayscale image deciding the size of the circles, with
+  /// the same aspect as the input. Dark areas get small
+  /// circles and light areas big ones, between
+  /// `--min-radius` and `--max-radius`
+  #[arg(long = "radius-map", value_hint = clap::ValueHint::FilePath)]
+  radius_map: Option<std::path::PathBuf>,
+
+  /// Size the circles by the detail in the input image,
+  /// small where there are edges and texture and big where
+  /// it's flat. Uses `--min-radius` and `--max-radius`
+  #[arg(long = "auto-size", conflicts_with = "radius_map")]
+  auto_size: bool,
+
+  /// Smallest radius used with `--radius-map` or
+  /// `--auto-size` (half the radius by default)
+  #[arg(long = "min-radius")]
+  min_radius: Option<f32>,
+
+  /// Largest radius used with `--radius-map` or
+  /// `--auto-size` (one and a half times the radius by
+  /// default)
+  #[arg(long = "max-radius")]
+  max_radius: Option<f32>,
+
+  /// Seconds of simulated time per step (1/60 by default).
+  /// Higher is faster but less stable
+  #[arg(long = "timescale")]
+  timescale: Option<f32>,
+
+  /// Physics substeps per step, between 1 and 64 (8 by
+  /// default). More makes circles stiffer but is slower
+  #[arg(long = "substeps")]
+  substeps: Option<usize>,
+
+  /// Downward pull in pixels per second squared, negative
+  /// pulls up (the height of the area by default)
+  #[arg(long = "gravity", allow_negative_numbers = true)]
+  gravity: Option<f32>,
+
+  /// Tilt gravity, can be repeated. Takes
+  /// `seconds:angle[,strength]`, with the angle in degrees
+  /// (90 is down) and the strength a multiple of
+  /// `--gravity`. Gravity blends from one key to the
+  /// next, e.g. `--tilt 4:90 --tilt 5:180 --tilt 7:90`
+  /// slides everything left and back
+  #[arg(long = "tilt", allow_negative_numbers = true)]
+  tilts: Vec<gravity::GravityKey>,
+
+  /// Shake the bowl side to side, can be repeated. Takes
+  /// `seconds:duration[,strength[,frequency]]`, with the
+  /// strength a multiple of `--gravity` (1 by default) and
+  /// the frequency in shakes per second (3 by default)
+  #[arg(long = "shake")]
+  shakes: Vec<gravity::Shake>,
+
+  /// Add a force field, can be repeated. Takes the field
+  /// (attract, repel, vortex, wind or drag) and settings,
+  /// e.g. `vortex:x=0.5,y=0.5,strength=2,reach=0.4,end=5`.
+  /// Strength is a multiple of the default gravity, or the
+  /// fraction of speed lost per second for drag. Attract,
+  /// repel and vortex take a center `x,y`, a `reach` and
+  /// a `falloff`. Wind takes an `angle`, and wind and
+  /// drag can be limited to a zone `x1,y1,x2,y2`. All
+  /// take `start` and `end` in seconds
+  #[arg(long = "field", value_parser = field::parse_field)]
+  fields: Vec<field::ForceField>,
+
+  /// Fraction of an overlap pushed apart every substep,
+  /// above 0 and at most 1 (0.9 by default). Lower lets
+  /// circles squish into each other
+  #[arg(long = "response")]
+  response: Option<f32>,
+
+  /// How much the radius wobbles between circles, as a
+  /// fraction of the radius between 0 and 0.5 (0.1 by
+  /// default)
+  #[arg(long = "radius-variance")]
+  radius_variance: Option<f32>,
+
+  /// Fraction of the free area filled with circles, above 0
+  /// and at most 1 (0.9 by default)
+  #[arg(long = "packing")]
+  packing: Option<f32>,
+
+  /// Steps to let circles settle after the last one spawns
+  /// (120 by default)
+  #[arg(long = "post-process")]
+  post_process: Option<usize>,
+
+  /// Number of threads used for the physics (all cores by
+  /// default). The output is the same for any thread count
+  #[arg(short = 'j', long = "threads")]
+  threads: Option<usize>,
+
+  /// Seed for the spawn pattern. The same seed and settings
+  /// reproduce the same animation. Derived from the image
+  /// when not given
+  #[arg(long = "seed")]
+  seed: Option<usize>,
+
+  /// Add an emitter, can be repeated. Takes the emitter
+  /// name (twin, spout, rain, jets, fountain or burst),
+  /// optionally followed by settings, e.g.
+  /// `spout:x=0.3,angle=60..120,speed=2,rate=1`. Positions
+  /// are fractions of the area, angles are in degrees with
+  /// 90 pointing down, speed is in pixels per substep and
+  /// rate in circles per step. Bursts take `at` (the step
+  /// to fire on) and `count` instead of a rate. Uses
+  /// `twin` by default
+  #[arg(short = 'e', long = "emitter", value_parser = emitter::parse_emitter)]
+  emitters: Vec<Box<dyn emitter::Emitter>>,
+
+  /// Shape of the bowl: `rect` (the default), `circle`,
+  /// `circle:x,y,radius`, `rounded`, `rounded:corner` or
+  /// `polygon:x,y;x,y;...`. Positions are fractions of the
+  /// area, and the radius and corner size are fractions of
+  /// the shorter side
+  #[arg(short = 'c', long = "container")]
+  container: Option<container::Container>,
+
+  /// How circles take their color from the image: the
+  /// pixel at their `center` (the default), the
+  /// `disk-average` of every pixel under them, the `median`
+  /// of those, or a `gaussian` weighted average favouring
+  /// the center. Averaging avoids stray pixels coloring a
+  /// whole circle on large images
+  #[arg(long = "sampling", value_enum)]
+  sampling: Option<color::Sampling>,
+
+  /// Average colors in linear light rather than on the
+  /// stored sRGB values, which keeps mixes of bright and
+  /// dark pixels from turning out too dark
+  #[arg(long = "linear-light")]
+  linear_light: bool,
+
+  /// Limit circles to a palette, either `kmeans:colors` or
+  /// `median-cut:colors` to pick that many colors from the
+  /// image, or a palette file with a hex color such as
+  /// `#ff8800` on each line or a GIMP `.gpl` palette.
+  /// Colors are matched by how alike they look, and every
+  /// frame of the GIF shares the palette
+  #[arg(long = "palette")]
+  palette: Option<palette::PaletteSource>,
+
+  /// Dither with `--palette`, passing the difference
+  /// between each circle's color and its palette color on
+  /// to the circles around it so gradients still show.
+  /// Optionally takes a strength between 0 and 1 (1 by
+  /// default)
+  #[arg(
+    long = "dither",
+    requires = "palette",
+    num_args = 0..=1,
+    default_missing_value = "1"
+  )]
+  dither: Option<f32>,
+
+  /// Color circles start with before taking on the image's:
+  /// `white`, `gray`, `rainbow` or a hex color like
+  /// `#ff8800`. Revealed over 2 seconds from spawning
+  /// unless `--reveal` is given
+  #[arg(long = "start-color")]
+  start_color: Option<transition::StartColor>,
+
+  /// How circles go from `--start-color` (white by default)
+  /// to the image's colors. `time:seconds` blends each one
+  /// in after it spawns, `rest:seconds` while it's resting
+  /// and `wave:seconds` sweeps top to bottom once
+  /// everything has settled. Seconds are 2 by default
+  #[arg(long = "reveal")]
+  reveal: Option<transition::Reveal>,
+
+  /// Trace the edge of the container in the output
+  #[arg(long = "outline")]
+  outline: bool,
+
+  /// Add a static obstacle, can be repeated. One of
+  /// `peg:x,y,radius`, `segment:x1,y1,x2,y2[,thickness]` or
+  /// `box:x1,y1,x2,y2`, in the same units as `--container`
+  #[arg(long = "obstacle")]
+  obstacles: Vec<obstacle::Obstacle>,
+
+  /// Take the obstacles out after this many seconds so the
+  /// image has no holes. By default they stay for the
+  /// whole animation
+  #[arg(long = "remove-obstacles")]
+  remove_obstacles: Option<f32>,
+
+  /// Draw the obstacles in the output
+  #[arg(long = "draw-obstacles")]
+  draw_obstacles: bool,
+
+  /// JSON scene file with extra setup such as obstacles,
+  /// force fields and a timeline of events. Options given
+  /// on the command line are added to it
+  #[arg(long = "scene", value_hint = clap::ValueHint::FilePath)]
+  scene: Option<std::path::PathBuf>,
+
+  /// Record the circles while preprocessing and replay them
+  /// instead of simulating everything a second time, which
+  /// takes about half as long. Large recordings are kept in
+  /// a temporary file
+  #[arg(long = "record", conflicts_with_all = ["save_state", "load_state"])]
+  record: bool,
+
+  /// Write the simulation state to this file once
+  /// preprocessing is done, so it can be reused with
+  /// `--load-state` to render again without preprocessing.
+  /// Nothing is written if preprocessing doesn't finish,
+  /// see `--checkpoint` for that
+  #[arg(long = "save-state", value_hint = clap::ValueHint::FilePath)]
+  save_state: Option<std::path::PathBuf>,
+
+  /// Save the progress of preprocessing to this file every
+  /// so often, and carry on from it when it was saved with
+  /// the same images and options, so a run cut short can
+  /// be picked up again. Once done the file holds the
+  /// result, so running again skips preprocessing. Recorded
+  /// frames are kept in files next to it
+  #[arg(
+    long = "checkpoint",
+    value_hint = clap::ValueHint::FilePath,
+    conflicts_with = "load_state"
+  )]
+  checkpoint: Option<std::path::PathBuf>,
+
+  /// Skip preprocessing and start from a state written by
+  /// `--save-state`. The input image is not needed, and the
+  /// output is the size the state was made at. Options that
+  /// change the simulation are already in the state, so
+  /// they can't be given with it
+  #[arg(
+    long = "load-state",
+    value_hint = clap::ValueHint::FilePath,
+    conflicts_with_all = [
+      "width", "height", "aspect", "radius", "radius_map", "auto_size",
+      "min_radius", "max_radius", "timescale", "substeps", "gravity", "tilts",
+      "shakes", "fields", "response", "radius_variance", "packing",
+      "post_process", "seed", "emitters", "container", "sampling",
+      "linear_light", "palette", "obstacles", "remove_obstacles", "scene",
+      "fit", "align", "letterbox", "change", "show",
+    ]
+  )]
+  load_state: Option<std::path::PathBuf>,
+
+  /// How the image is laid over the bowl when their shapes
+  /// differ: stretched to fit exactly (the default), shown
+  /// whole with bars left over, or covering the bowl with
+  /// the edges cropped off
+  #[arg(long = "fit", value_enum)]
+  fit: Option<color::Fit>,
+
+  /// Where the image sits in the bowl with `--fit contain`,
+  /// or the part of it kept with `--fit cover`, as `x,y`
+  /// fractions. `0,0` is the top left and `0.5,0.5` the
+  /// center (the default)
+  #[arg(long = "align", value_parser = color::parse_align)]
+  align: Option<(f32, f32)>,
+
+  /// Color of the circles outside the image with `--fit
+  /// contain`, as a hex color (black by default), or `skip`
+  /// to leave them out of the frames
+  #[arg(long = "letterbox", requires = "fit")]
+  letterbox: Option<color::Letterbox>,
+
+  /// How the bowl goes from one image to the next with
+  /// several `-i`: `shake[:seconds[,speed]]` kicks the
+  /// circles up from below, and `drain[:seconds]` lets
+  /// them fall out and sends them back in. Shakes for 2
+  /// seconds by default
+  #[arg(long = "change")]
+  change: Option<slideshow::Change>,
+
+  /// Seconds each image is shown before changing to the
+  /// next (2 by default)
+  #[arg(long = "show")]
+  show: Option<f32>,
+
+  /// Add an outro after the image forms, can be repeated to
+  /// run several in a row. One of `hold[:seconds]`,
+  /// `drain[:seconds]`, `explode[:seconds[,speed]]` or
+  /// `fade[:seconds]`, in simulated seconds. With `--loop`
+  /// the end blends back into the first frame
+  #[arg(long = "outro")]
+  outros: Vec<outro::Outro>,
+
+  /// Play the animation forward (the default), in reverse
+  /// starting from the finished image, or forward and then
+  /// back again
+  #[arg(long = "direction", value_enum)]
+  direction: Option<Direction>,
+
   /// Loop the GIF
   #[arg(short = 'l', long = "loop")]
   looping: bool,
 }
 
-fn open_image<P: AsRef<Path>>(path: P) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+fn open_image<P: AsRef<Path>>(path: P) -> image::DynamicImage {
   let image = match image::io::Reader::open(path) {
     Ok(i) => i,
     Err(e) => {
@@ -130,7 +882,7 @@ fn open_image<P: AsRef<Path>>(path: P) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
       std::process::exit(1);
     },
   };
-  decoded.to_rgb8()
+  decoded
 }
 
 fn main() {
@@ -142,18 +894,226 @@ fn main() {
     eprintln!("Physics step cannot be 0");
     std::process::exit(1);
   }
-  let radius = args.radius.unwrap_or(8.0);
-  if radius < 1.0 || radius > 50.0 {
-    eprintln!("Invalid radius {}", radius);
-    eprintln!("Must be between 1.0 and 50.0 inclusive");
-    std::process::exit(1);
+  let defaults = SimulationConfig::default();
+  let radius = args.radius.unwrap_or(defaults.circle_radius);
+  let threads = args.threads.unwrap_or_else(|| {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+  });
+
+  let mut scene = match &args.scene {
+    Some(path) => match scene::Scene::load(path) {
+      Ok(scene) => scene,
+      Err(e) => {
+        eprintln!("Error loading scene from '{}':", path.display());
+        eprintln!("{}", e);
+        std::process::exit(1);
+      },
+    },
+    None => scene::Scene::default(),
+  };
+  scene.obstacles.extend(args.obstacles);
+  if args.remove_obstacles.is_some() {
+    scene.remove_obstacles_at = args.remove_obstacles;
+  }
+  scene.gravity.keys.extend(args.tilts);
+  scene.gravity.shakes.extend(args.shakes);
+  scene.fields.extend(args.fields);
+
+  let images: Vec<_> = match args.load_state {
+    Some(_) => vec![],
+    None => args.input.iter().map(|i| open_image(i).to_rgb8()).collect(),
+  };
+  // The bowl is shaped for the first image, so the others
+  // get stretched or cut to fit it
+  let ratio =
+    |i: &ImageBuffer<Rgb<u8>, Vec<u8>>| i.width() as f32 / i.height() as f32;
+  if let Some(first) = images.first().map(ratio) {
+    for (path, image) in args.input.iter().zip(&images).skip(1) {
+      if (ratio(image) / first - 1.0).abs() > 0.01 {
+        eprintln!(
+          "Warning: '{}' has a different aspect ratio than the first image, \
+           so it will be fit to the same area",
+          path.display()
+        );
+      }
+    }
+  }
+  let aspect = args.aspect.map(|aspect| match aspect {
+    Aspect::Auto => images
+      .first()
+      .map_or(1.0, |i| i.width() as f32 / i.height() as f32),
+    Aspect::Ratio(ratio) => ratio,
+  });
+  let (width, height) = match output_size(args.width, args.height, aspect) {
+    Ok(size) => size,
+    Err(e) => {
+      eprintln!("{}", e);
+      std::process::exit(1);
+    },
+  };
+  let mut builder = SimulationConfig::builder()
+    .size(width as f32, height as f32)
+    .circle_radius(radius)
+    .threads(threads)
+    .emitters(args.emitters)
+    .container(args.container.unwrap_or_default())
+    .obstacles(scene.obstacles, scene.remove_obstacles_at)
+    .gravity_track(scene.gravity)
+    .fields(scene.fields)
+    .timeline(scene.timeline)
+    .auto_size(args.auto_size)
+    .radius_range(
+      args.min_radius.unwrap_or((radius * 0.5).max(1.0)),
+      args.max_radius.unwrap_or((radius * 1.5).min(50.0)),
+    )
+    .timescale(args.timescale.unwrap_or(defaults.timescale))
+    .substeps(args.substeps.unwrap_or(defaults.substeps))
+    .response_mod(args.response.unwrap_or(defaults.response_mod))
+    .radius_variance(args.radius_variance.unwrap_or(defaults.radius_variance))
+    .packing(args.packing.unwrap_or(defaults.packing))
+    .post_process(args.post_process.unwrap_or(defaults.post_process));
+  if let Some(gravity) = args.gravity {
+    builder = builder.gravity(gravity);
+  }
+  if args.record {
+    builder = builder.record(step);
+  }
+  if let Some(path) = args.checkpoint {
+    builder = builder.checkpoint(path);
   }
+  builder =
+    builder.sampling(args.sampling.unwrap_or_default(), args.linear_light);
+  if let Some(palette) = args.palette {
+    builder = builder.palette(palette);
+  }
+  if let Some(strength) = args.dither {
+    builder = builder.dither(strength);
+  }
+  builder = builder.fit(
+    args.fit.unwrap_or_default(),
+    args.align.unwrap_or(defaults.align),
+    args.letterbox.unwrap_or_default(),
+  );
+  builder = builder.slideshow(
+    args.change.unwrap_or_default(),
+    args.show.unwrap_or(defaults.show),
+  );
+  let mut config = match builder.build() {
+    Ok(config) => config,
+    Err(e) => {
+      eprintln!("{}", e);
+      std::process::exit(1);
+    },
+  };
 
-  let image = open_image(args.input);
-  let (sim, it, max) = pollster::block_on(preprocess(image, radius));
-  let mut draw = pollster::block_on(QuickDraw::new(512, 512, 1000));
-  let frames = pollster::block_on(simulate(&mut draw, sim, it, step, max));
-  let gif = pollster::block_on(encode(frames, args.looping));
+  if args.load_state.is_some() && args.command.is_some() {
+    eprintln!("The verify command needs an input image, not a saved state");
+    std::process::exit(1);
+  }
+  let (mut sim, it, max, recording) = match args.load_state {
+    Some(path) => match state::load(&path) {
+      Ok((sim, it)) => {
+        println!("Using seed {}", sim.seed());
+        let max = sim.max_circles;
+        (sim, it, max, None)
+      },
+      Err(e) => {
+        eprintln!("Error loading state from '{}':", path.display());
+        eprintln!("{}", e);
+        std::process::exit(1);
+      },
+    },
+    None => {
+      let image = &images[0];
+      config.size_field = args.radius_map.map(|path| {
+        let map = open_image(path).to_luma8();
+        let aspect = |w: u32, h: u32| w as f32 / h as f32;
+        if (aspect(map.width(), map.height())
+          - aspect(image.width(), image.height()))
+        .abs()
+          > 0.01
+        {
+          println!("Radius map has a different aspect, it will be stretched");
+        }
+        size::SizeField::from_image(&map)
+      });
+      let seed = args.seed.unwrap_or_else(|| Simulation::image_seed(image));
+      println!("Using seed {}", seed);
+      config.seed = Some(seed);
+      if let Some(Command::Verify) = args.command {
+        match pollster::block_on(verify(images, &config)) {
+          Ok(steps) => {
+            println!("The replay matches preprocessing over {} steps", steps);
+            return;
+          },
+          Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+          },
+        }
+      }
+      pollster::block_on(preprocess(images, &config))
+    },
+  };
+  sim.threads = threads;
+  if let Some(path) = args.save_state {
+    if let Err(e) = state::save(&path, &sim, it) {
+      eprintln!("Error saving state to '{}':", path.display());
+      eprintln!("{}", e);
+      std::process::exit(1);
+    }
+  }
+  let (width, height) = frame_size(&sim);
+  let mut draw = pollster::block_on(QuickDraw::new(width, height, 1000));
+  let mut overlays = Overlays::default();
+  if args.outline || !matches!(sim.container(), container::Container::Rect) {
+    let outline = args.outline.then_some(helper::Color(200, 200, 200));
+    overlays.base = Some(sim.container().overlay(
+      sim.area_size(),
+      width,
+      height,
+      helper::Color(0, 0, 0),
+      outline,
+    ));
+  }
+  if args.draw_obstacles && !sim.obstacles().is_empty() {
+    let mut overlay = overlays
+      .base
+      .clone()
+      .unwrap_or_else(|| Overlay::new(width, height));
+    obstacle::draw(
+      sim.obstacles(),
+      &mut overlay,
+      sim.area_size(),
+      helper::Color(120, 120, 120),
+    );
+    overlays.obstacles = Some(overlay);
+  }
+  let transition = match (args.start_color, args.reveal) {
+    (None, None) => None,
+    (start, reveal) => Some(transition::Transition {
+      start: start.unwrap_or(transition::StartColor::White),
+      reveal: reveal.unwrap_or(transition::Reveal::Time { seconds: 2.0 }),
+    }),
+  };
+  let palette = sim
+    .palette()
+    .map_or(vec![], |p| palette::GifPalette::new(&p).bytes());
+  let frames = pollster::block_on(simulate(
+    &mut draw,
+    sim,
+    it,
+    step,
+    max,
+    Renderer::new(overlays, recording, transition),
+    &outro::Ending {
+      outros: args.outros,
+      looping: args.looping,
+      direction: args.direction.unwrap_or_default(),
+    },
+  ));
+  let gif =
+    pollster::block_on(encode(frames, (width, height), args.looping, &palette));
   let mut file = match std::fs::File::create(output.clone()) {
     Ok(f) => f,
     Err(e) => {
@@ -163,7 +1123,7 @@ fn main() {
     },
   };
 
-  match file.write(&gif) {
+  match file.write_all(&gif) {
     Ok(_) => {
       println!("Successfully created file at '{}'", output.display());
     },
@@ -0,0 +1,241 @@
+use std::{f32::consts::PI, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  container::{box_distance, length},
+  draw::Overlay,
+  helper::{parse_numbers, Color, Vector2},
+};
+
+/// Static collider inside the simulation area. Like
+/// [`Container`](crate::container::Container), positions
+/// are fractions of the area and sizes that aren't along an
+/// axis are fractions of the shorter side
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Obstacle {
+  /// A round peg
+  Peg { center: (f32, f32), radius: f32 },
+  /// A line from `start` to `end`, `thickness` wide
+  Segment {
+    start: (f32, f32),
+    end: (f32, f32),
+    thickness: f32,
+  },
+  /// An axis aligned box between two corners
+  Box { min: (f32, f32), max: (f32, f32) },
+}
+
+impl Obstacle {
+  fn scale(area: (f32, f32), point: (f32, f32)) -> Vector2 {
+    Vector2::new(point.0 * area.0, point.1 * area.1)
+  }
+
+  /// Signed distance from `p` to the surface, negative
+  /// inside, together with the outward normal
+  pub fn distance(&self, area: (f32, f32), p: Vector2) -> (f32, Vector2) {
+    let short = area.0.min(area.1);
+    match self {
+      Self::Peg { center, radius } => {
+        let offset = p - Self::scale(area, *center);
+        let d = length(offset);
+        let normal = if d > 0.0 {
+          offset * (1.0 / d)
+        } else {
+          Vector2::new(0.0, -1.0)
+        };
+        (d - radius * short, normal)
+      },
+      Self::Segment {
+        start,
+        end,
+        thickness,
+      } => {
+        let a = Self::scale(area, *start);
+        let edge = Self::scale(area, *end) - a;
+        let len2 = edge.length2();
+        let t = if len2 > 0.0 {
+          (((p.x - a.x) * edge.x + (p.y - a.y) * edge.y) / len2).clamp(0.0, 1.0)
+        } else {
+          0.0
+        };
+        let offset = p - (a + edge * t);
+        let d = length(offset);
+        let normal = if d > 0.0 {
+          offset * (1.0 / d)
+        } else if len2 > 0.0 {
+          Vector2::new(edge.y, -edge.x) * (1.0 / len2.sqrt())
+        } else {
+          Vector2::new(0.0, -1.0)
+        };
+        (d - thickness * short / 2.0, normal)
+      },
+      Self::Box { min, max } => {
+        let min = Self::scale(area, *min);
+        let max = Self::scale(area, *max);
+        let center = (min + max) * 0.5;
+        let half = (max - min) * 0.5;
+        let half = Vector2::new(half.x.abs(), half.y.abs());
+        box_distance(p, center, half)
+      },
+    }
+  }
+
+  /// Pushes a circle at `p` out of the obstacle. Returns
+  /// the new position and the surface normal, or `None`
+  /// when they don't touch
+  #[inline]
+  pub fn push_out(
+    &self,
+    area: (f32, f32),
+    p: Vector2,
+    radius: f32,
+  ) -> Option<(Vector2, Vector2)> {
+    let (d, normal) = self.distance(area, p);
+    if d < radius {
+      Some((p + normal * (radius - d), normal))
+    } else {
+      None
+    }
+  }
+
+  /// Checks the obstacle has a size and its points are
+  /// numbers, describing the problem if not
+  pub fn validate(&self) -> Result<(), String> {
+    let finite = |p: &(f32, f32)| p.0.is_finite() && p.1.is_finite();
+    match self {
+      Self::Peg { center, radius } => {
+        if !(*radius > 0.0 && radius.is_finite() && finite(center)) {
+          return Err(format!(
+            "Invalid peg obstacle, the radius {} must be above 0",
+            radius
+          ));
+        }
+      },
+      Self::Segment {
+        start,
+        end,
+        thickness,
+      } => {
+        if !(finite(start) && finite(end)) || start == end {
+          return Err(
+            "Invalid segment obstacle, the ends must be numbers and not the \
+             same point"
+              .into(),
+          );
+        }
+        if !(*thickness > 0.0 && thickness.is_finite()) {
+          return Err(format!(
+            "Invalid segment obstacle, the thickness {} must be above 0",
+            thickness
+          ));
+        }
+      },
+      Self::Box { min, max } => {
+        if !(finite(min) && finite(max) && min.0 < max.0 && min.1 < max.1) {
+          return Err(
+            "Invalid box obstacle, the corners must be numbers and the box \
+             must have an area"
+              .into(),
+          );
+        }
+      },
+    }
+    Ok(())
+  }
+
+  /// Area the obstacle covers
+  pub fn area(&self, area: (f32, f32)) -> f32 {
+    let short = area.0.min(area.1);
+    match self {
+      Self::Peg { radius, .. } => PI * (radius * short).powi(2),
+      Self::Segment {
+        start,
+        end,
+        thickness,
+      } => {
+        let length =
+          length(Self::scale(area, *end) - Self::scale(area, *start));
+        let thickness = thickness * short;
+        length * thickness + PI * (thickness / 2.0).powi(2)
+      },
+      Self::Box { min, max } => {
+        let size = Self::scale(area, *max) - Self::scale(area, *min);
+        (size.x * size.y).abs()
+      },
+    }
+  }
+}
+
+/// Draws `obstacles` in `color` onto an overlay covering
+/// the simulation area
+pub fn draw(
+  obstacles: &[Obstacle],
+  overlay: &mut Overlay,
+  area: (f32, f32),
+  color: Color,
+) {
+  let scale = (
+    area.0 / overlay.width() as f32,
+    area.1 / overlay.height() as f32,
+  );
+  let pixel = scale.0.max(scale.1);
+  for y in 0..overlay.height() {
+    for x in 0..overlay.width() {
+      let p =
+        Vector2::new((x as f32 + 0.5) * scale.0, (y as f32 + 0.5) * scale.1);
+      let d = obstacles
+        .iter()
+        .map(|o| o.distance(area, p).0)
+        .fold(f32::MAX, f32::min);
+      let alpha = (0.5 - d / pixel).clamp(0.0, 1.0);
+      if alpha > 0.0 {
+        overlay.set(x, y, color, alpha);
+      }
+    }
+  }
+}
+
+impl FromStr for Obstacle {
+  type Err = String;
+
+  /// Parses `peg:x,y,radius`,
+  /// `segment:x1,y1,x2,y2[,thickness]` or `box:x1,y1,x2,
+  /// y2`
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    let (name, params) = spec.split_once(':').unwrap_or((spec, ""));
+    let values = parse_numbers(params)?;
+    let obstacle = match (name, values.as_slice()) {
+      ("peg", [x, y, radius]) => Self::Peg {
+        center: (*x, *y),
+        radius: *radius,
+      },
+      ("segment", [x1, y1, x2, y2]) => Self::Segment {
+        start: (*x1, *y1),
+        end: (*x2, *y2),
+        thickness: 0.01,
+      },
+      ("segment", [x1, y1, x2, y2, thickness]) => Self::Segment {
+        start: (*x1, *y1),
+        end: (*x2, *y2),
+        thickness: *thickness,
+      },
+      ("box", [x1, y1, x2, y2]) => Self::Box {
+        min: (x1.min(*x2), y1.min(*y2)),
+        max: (x1.max(*x2), y1.max(*y2)),
+      },
+      ("peg" | "segment" | "box", _) => {
+        return Err(format!("wrong number of values for {}", name))
+      },
+      _ => {
+        return Err(format!(
+          "unknown obstacle '{}', expected one of peg, segment or box",
+          name
+        ))
+      },
+    };
+    obstacle.validate()?;
+    Ok(obstacle)
+  }
+}
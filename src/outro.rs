@@ -0,0 +1,107 @@
+use std::str::FromStr;
+
+use crate::{
+  helper::parse_numbers, sim::Simulation, timeline::Event, Direction,
+};
+
+/// What happens after the image has formed. Outros run one
+/// after another, each for `seconds` of simulated time
+#[derive(Clone, Debug)]
+pub enum Outro {
+  /// Keeps showing the finished image
+  Hold { seconds: f32 },
+  /// Opens the floor so everything falls out, ending early
+  /// once the bowl is empty
+  Drain { seconds: f32 },
+  /// Blows circles away from the center, then lets them
+  /// fall out. `speed` is in pixels per substep
+  Explode { seconds: f32, speed: f32 },
+  /// Fades to the background
+  Fade { seconds: f32 },
+}
+
+impl Outro {
+  pub fn seconds(&self) -> f32 {
+    match self {
+      Self::Hold { seconds }
+      | Self::Drain { seconds }
+      | Self::Explode { seconds, .. }
+      | Self::Fade { seconds } => *seconds,
+    }
+  }
+
+  /// Sets the simulation up for the outro
+  pub fn start(&self, sim: &mut Simulation) {
+    match self {
+      Self::Hold { .. } | Self::Fade { .. } => {},
+      Self::Drain { .. } => sim.apply(Event::OpenFloor),
+      Self::Explode { speed, .. } => {
+        sim.apply(Event::Impulse {
+          speed: *speed,
+          angle: 0.0,
+          center: Some((0.5, 0.5)),
+        });
+        sim.apply(Event::OpenFloor);
+      },
+    }
+  }
+
+  /// Whether the outro can stop before its time is up
+  pub fn done(&self, sim: &Simulation) -> bool {
+    matches!(self, Self::Drain { .. } | Self::Explode { .. })
+      && sim.circles.is_empty()
+  }
+}
+
+/// How the animation ends once the image has formed
+#[derive(Clone, Debug, Default)]
+pub struct Ending {
+  pub outros: Vec<Outro>,
+  /// Blend the last frame back into the first
+  pub looping: bool,
+  pub direction: Direction,
+}
+
+impl FromStr for Outro {
+  type Err = String;
+
+  /// Parses `hold[:seconds]`, `drain[:seconds]`,
+  /// `explode[:seconds[,speed]]` or `fade[:seconds]`
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    let (name, params) = spec.split_once(':').unwrap_or((spec, ""));
+    let values = parse_numbers(params)?;
+    let outro = match (name, values.as_slice()) {
+      ("hold", []) => Self::Hold { seconds: 2.0 },
+      ("hold", [seconds]) => Self::Hold { seconds: *seconds },
+      ("drain", []) => Self::Drain { seconds: 6.0 },
+      ("drain", [seconds]) => Self::Drain { seconds: *seconds },
+      ("explode", []) => Self::Explode {
+        seconds: 4.0,
+        speed: 8.0,
+      },
+      ("explode", [seconds]) => Self::Explode {
+        seconds: *seconds,
+        speed: 8.0,
+      },
+      ("explode", [seconds, speed]) => Self::Explode {
+        seconds: *seconds,
+        speed: *speed,
+      },
+      ("fade", []) => Self::Fade { seconds: 1.0 },
+      ("fade", [seconds]) => Self::Fade { seconds: *seconds },
+      ("hold" | "drain" | "explode" | "fade", _) => {
+        return Err(format!("wrong number of values for {}", name))
+      },
+      _ => {
+        return Err(format!(
+          "unknown outro '{}', expected one of hold, drain, explode or fade",
+          name
+        ))
+      },
+    };
+    if outro.seconds().is_nan() || outro.seconds() <= 0.0 {
+      return Err(format!("the {} outro must last more than 0 seconds", name));
+    }
+    Ok(outro)
+  }
+}
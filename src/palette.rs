@@ -0,0 +1,318 @@
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+use crate::{color::lab, helper::Color};
+
+// Most colors a generated palette can have, leaving room
+// for the background in a GIF palette
+const MAX_COLORS: usize = 255;
+// Rounds of k-means refinement
+const KMEANS_ROUNDS: usize = 16;
+
+/// A fixed set of colors that circles are snapped to
+#[derive(Clone, Debug, Default)]
+pub struct Palette {
+  pub colors: Vec<Color>,
+  lab: Vec<[f32; 3]>,
+}
+
+impl Palette {
+  pub fn new(colors: Vec<Color>) -> Self {
+    let lab = colors.iter().map(|c| lab(*c)).collect();
+    Self { colors, lab }
+  }
+
+  /// Reads a palette file, either a GIMP `.gpl` palette or
+  /// a list of hex colors such as `#ff8800`, one per line
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let colors = if text.trim_start().starts_with("GIMP Palette") {
+      parse_gpl(&text)?
+    } else {
+      parse_hex_list(&text)?
+    };
+    if colors.is_empty() {
+      return Err(format!("no colors found in '{}'", path.display()));
+    }
+    if colors.len() > MAX_COLORS {
+      return Err(format!(
+        "'{}' has {} colors, at most {} are allowed",
+        path.display(),
+        colors.len(),
+        MAX_COLORS
+      ));
+    }
+    Ok(Self::new(colors))
+  }
+
+  /// Parses a `#rrggbb` or `rrggbb` color
+  pub fn parse_hex(word: &str) -> Result<Color, String> {
+    let hex = word.trim_start_matches('#');
+    let value = match hex.len() {
+      6 => u32::from_str_radix(hex, 16).ok(),
+      _ => None,
+    }
+    .ok_or_else(|| format!("invalid hex color '{}'", word))?;
+    Ok(Color((value >> 16) as u8, (value >> 8) as u8, value as u8))
+  }
+
+  /// Splits the colors into `count` boxes, each time
+  /// halving the box with the widest channel at its median,
+  /// and takes the average of each box
+  pub fn median_cut(colors: &[Color], count: usize) -> Self {
+    let mut boxes = vec![colors.to_vec()];
+    while boxes.len() < count {
+      // Box with the widest channel, as (box, channel, range)
+      let widest = boxes
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.len() > 1)
+        .flat_map(|(i, b)| {
+          (0..3).map(move |channel| {
+            let values = b.iter().map(|c| channel_of(*c, channel));
+            let range = values.clone().max().unwrap() - values.min().unwrap();
+            (i, channel, range)
+          })
+        })
+        .max_by_key(|&(i, channel, range)| {
+          (range, std::cmp::Reverse((i, channel)))
+        });
+      let Some((i, channel, range)) = widest else {
+        break;
+      };
+      if range == 0 {
+        break;
+      }
+      let mut split = boxes.swap_remove(i);
+      split.sort_by_key(|c| (channel_of(*c, channel), c.0, c.1, c.2));
+      let upper = split.split_off(split.len() / 2);
+      boxes.push(split);
+      boxes.push(upper);
+    }
+    let mut averages: Vec<Color> = boxes
+      .iter()
+      .filter(|b| !b.is_empty())
+      .map(|b| {
+        let sum = b.iter().fold([0u64; 3], |s, c| {
+          [s[0] + c.0 as u64, s[1] + c.1 as u64, s[2] + c.2 as u64]
+        });
+        let n = b.len() as u64;
+        Color(
+          ((sum[0] + n / 2) / n) as u8,
+          ((sum[1] + n / 2) / n) as u8,
+          ((sum[2] + n / 2) / n) as u8,
+        )
+      })
+      .collect();
+    averages.sort_by_key(|c| (c.0, c.1, c.2));
+    Self::new(averages)
+  }
+
+  /// Starts from [`Palette::median_cut`] and moves each
+  /// entry to the middle of the colors nearest to it in
+  /// CIELAB. Entries end up as the input color closest to
+  /// that middle, so only colors from the input are used
+  pub fn kmeans(colors: &[Color], count: usize) -> Self {
+    let mut palette = Self::median_cut(colors, count);
+    let points: Vec<[f32; 3]> = colors.iter().map(|c| lab(*c)).collect();
+    for _ in 0..KMEANS_ROUNDS {
+      let mut sums = vec![([0.0f32; 3], 0usize); palette.lab.len()];
+      for point in &points {
+        let (sum, n) = &mut sums[palette.nearest_index(*point)];
+        for (s, v) in sum.iter_mut().zip(point) {
+          *s += v;
+        }
+        *n += 1;
+      }
+      let mut moved = false;
+      for (entry, (sum, n)) in sums.iter().enumerate() {
+        if *n == 0 {
+          continue;
+        }
+        let center = sum.map(|s| s / *n as f32);
+        // Input color closest to the center
+        let closest = (0..points.len())
+          .min_by(|&a, &b| {
+            distance(points[a], center).total_cmp(&distance(points[b], center))
+          })
+          .unwrap();
+        let color = colors[closest];
+        if palette.colors[entry] != color {
+          palette.colors[entry] = color;
+          palette.lab[entry] = points[closest];
+          moved = true;
+        }
+      }
+      if !moved {
+        break;
+      }
+    }
+    palette
+  }
+
+  // Index of the entry closest to `point`, a CIELAB color
+  fn nearest_index(&self, point: [f32; 3]) -> usize {
+    (0..self.lab.len())
+      .min_by(|&a, &b| {
+        distance(self.lab[a], point).total_cmp(&distance(self.lab[b], point))
+      })
+      .unwrap_or(0)
+  }
+
+  /// Closest entry to `color` in CIELAB
+  pub fn nearest(&self, color: Color) -> Color {
+    self.colors[self.nearest_index(lab(color))]
+  }
+
+  /// Replaces every color with its closest entry
+  pub fn snap(&self, colors: &mut [Color]) {
+    let mut cache = HashMap::new();
+    for color in colors {
+      *color = *cache
+        .entry((color.0, color.1, color.2))
+        .or_insert_with(|| self.nearest(*color));
+    }
+  }
+}
+
+/// Where the palette comes from
+#[derive(Clone, Debug)]
+pub enum PaletteSource {
+  File(Palette),
+  KMeans(usize),
+  MedianCut(usize),
+}
+
+impl PaletteSource {
+  /// The palette for an image whose circles have `colors`
+  pub fn build(&self, colors: &[Color]) -> Palette {
+    match self {
+      Self::File(palette) => palette.clone(),
+      Self::KMeans(count) => Palette::kmeans(colors, *count),
+      Self::MedianCut(count) => Palette::median_cut(colors, *count),
+    }
+  }
+}
+
+impl FromStr for PaletteSource {
+  type Err = String;
+
+  /// Parses `kmeans:colors`, `median-cut:colors` or the
+  /// path to a palette file
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    let count = |n: &str| match n.trim().parse::<usize>() {
+      Ok(n) if (1..=MAX_COLORS).contains(&n) => Ok(n),
+      _ => Err(format!(
+        "invalid color count '{}', must be between 1 and {}",
+        n, MAX_COLORS
+      )),
+    };
+    match spec.split_once(':') {
+      Some(("kmeans", n)) => Ok(Self::KMeans(count(n)?)),
+      Some(("median-cut", n)) => Ok(Self::MedianCut(count(n)?)),
+      _ => Palette::load(spec).map(Self::File).map_err(|e| {
+        format!(
+          "expected kmeans:colors, median-cut:colors or a palette file, could \
+           not read '{}': {}",
+          spec, e
+        )
+      }),
+    }
+  }
+}
+
+/// Maps RGBA frames onto one palette shared by every frame
+/// of a GIF, so colors don't shift between frames. Pixels
+/// not in the palette, such as the edges of circles, get
+/// the closest entry
+pub struct GifPalette {
+  palette: Palette,
+  cache: HashMap<[u8; 3], u8>,
+}
+
+impl GifPalette {
+  /// The colors of `palette` plus black for the background
+  pub fn new(palette: &Palette) -> Self {
+    let mut colors = palette.colors.clone();
+    if !colors.contains(&Color(0, 0, 0)) {
+      colors.push(Color(0, 0, 0));
+    }
+    colors.truncate(256);
+    Self {
+      palette: Palette::new(colors),
+      cache: HashMap::new(),
+    }
+  }
+
+  /// The palette as RGB bytes, for the GIF header
+  pub fn bytes(&self) -> Vec<u8> {
+    self
+      .palette
+      .colors
+      .iter()
+      .flat_map(|c| [c.0, c.1, c.2])
+      .collect()
+  }
+
+  /// Palette indices of each pixel of an RGBA frame
+  pub fn indices(&mut self, rgba: &[u8]) -> Vec<u8> {
+    rgba
+      .chunks_exact(4)
+      .map(|p| {
+        let key = [p[0], p[1], p[2]];
+        *self.cache.entry(key).or_insert_with(|| {
+          self.palette.nearest_index(lab(Color(p[0], p[1], p[2]))) as u8
+        })
+      })
+      .collect()
+  }
+}
+
+fn channel_of(color: Color, channel: usize) -> u8 {
+  [color.0, color.1, color.2][channel]
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+  (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+}
+
+// GIMP palettes start with a `GIMP Palette` line and
+// `Name:` and `Columns:` headers, followed by `r g b name`
+// lines. `#` starts a comment
+fn parse_gpl(text: &str) -> Result<Vec<Color>, String> {
+  let mut colors = vec![];
+  for line in text.lines().skip(1) {
+    let line = line.trim();
+    if line.is_empty()
+      || line.starts_with('#')
+      || line.starts_with("Name:")
+      || line.starts_with("Columns:")
+    {
+      continue;
+    }
+    let values: Vec<u8> = line
+      .split_whitespace()
+      .take(3)
+      .map(|v| v.parse::<u8>())
+      .collect::<Result<_, _>>()
+      .map_err(|_| format!("invalid palette line '{}'", line))?;
+    let [r, g, b] = values[..] else {
+      return Err(format!("invalid palette line '{}'", line));
+    };
+    colors.push(Color(r, g, b));
+  }
+  Ok(colors)
+}
+
+// One `#rrggbb` or `rrggbb` color per line, ignoring blank
+// lines and anything after the color
+fn parse_hex_list(text: &str) -> Result<Vec<Color>, String> {
+  let mut colors = vec![];
+  for line in text.lines() {
+    let Some(word) = line.split_whitespace().next() else {
+      continue;
+    };
+    colors.push(Palette::parse_hex(word)?);
+  }
+  Ok(colors)
+}
@@ -0,0 +1,248 @@
+use std::{
+  fs::File,
+  io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write},
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::helper::Vector2;
+
+// Bytes kept in memory before moving them to disk
+const SPILL_BYTES: usize = 256 << 20;
+// Bytes per circle: the index, both coordinates, the radius
+// and the time spent resting
+const SAMPLE_BYTES: usize = 12;
+// Positions are stored over this many areas, centered on
+// the area, so circles slightly outside still fit
+const SPAN: f32 = 2.0;
+// Steps of a quantized radius per pixel
+const RADIUS_STEPS: f32 = 256.0;
+// Steps of a quantized resting time per second
+const REST_STEPS: f32 = 100.0;
+
+/// A circle in a recorded frame
+#[derive(Copy, Clone)]
+pub struct Sample {
+  /// Spawn index, used to look up the color
+  pub index: usize,
+  pub position: Vector2,
+  pub radius: f32,
+  /// Seconds spent resting
+  pub rested: f32,
+}
+
+// Where a frame is stored
+#[derive(Copy, Clone, Serialize, Deserialize)]
+struct Entry {
+  offset: usize,
+  count: usize,
+  obstacles: bool,
+  time: f32,
+}
+
+/// Circles at every frame, captured while preprocessing so
+/// rendering can replay them instead of simulating a
+/// second time. Positions are quantized to a fraction of a
+/// pixel, and once the recording grows large the oldest
+/// frames are moved to a temporary file. Only the frames
+/// moved to a file kept with [`Recording::keep`] are
+/// saved along with it
+#[derive(Serialize, Deserialize)]
+pub struct Recording {
+  every: usize,
+  area: (f32, f32),
+  frames: Vec<Entry>,
+  #[serde(skip)]
+  memory: Vec<u8>,
+  // Bytes before the ones in `memory`, moved to `file`
+  spilled: usize,
+  // Where the file stays once the recording is dropped
+  kept: Option<PathBuf>,
+  // Opened when first needed
+  #[serde(skip)]
+  file: Option<(File, PathBuf)>,
+}
+
+impl Recording {
+  /// An empty recording taking a frame every `every` steps
+  pub fn new(every: usize, area: (f32, f32)) -> Self {
+    Self {
+      every: every.max(1),
+      area,
+      frames: vec![],
+      memory: vec![],
+      spilled: 0,
+      kept: None,
+      file: None,
+    }
+  }
+
+  /// Steps between frames
+  pub fn every(&self) -> usize {
+    self.every
+  }
+
+  pub fn len(&self) -> usize {
+    self.frames.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.frames.is_empty()
+  }
+
+  /// Adds a frame at `time` seconds, with whether the
+  /// obstacles are shown in it
+  pub fn push(
+    &mut self,
+    circles: impl ExactSizeIterator<Item = Sample>,
+    obstacles: bool,
+    time: f32,
+  ) -> Result<()> {
+    self.frames.push(Entry {
+      offset: self.spilled + self.memory.len(),
+      count: circles.len(),
+      obstacles,
+      time,
+    });
+    let (w, h) = self.area;
+    let coord = |v: f32, size: f32| {
+      let t = (v / size + (SPAN - 1.0) * 0.5) / SPAN;
+      (t.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+    };
+    for c in circles {
+      let radius = (c.radius * RADIUS_STEPS).round().min(u16::MAX as f32);
+      self.memory.extend((c.index as u32).to_le_bytes());
+      self.memory.extend(coord(c.position.x, w).to_le_bytes());
+      self.memory.extend(coord(c.position.y, h).to_le_bytes());
+      self.memory.extend((radius as u16).to_le_bytes());
+      let rested = (c.rested * REST_STEPS).round().min(u16::MAX as f32);
+      self.memory.extend((rested as u16).to_le_bytes());
+    }
+    if self.memory.len() >= SPILL_BYTES {
+      self.spill()?;
+    }
+    Ok(())
+  }
+
+  /// Moves the frames to the file at `path`, which is left
+  /// behind once the recording is dropped, so a saved copy
+  /// of the recording can read them later. Later frames go
+  /// there too
+  pub fn keep(&mut self, path: &Path) -> Result<()> {
+    if self.kept.is_none() {
+      if let Some((file, old)) = self.file.take() {
+        // Copied when the two are on different drives
+        if std::fs::rename(&old, path).is_err() {
+          if let Err(e) = std::fs::copy(&old, path) {
+            self.file = Some((file, old));
+            return Err(e);
+          }
+          let _ = std::fs::remove_file(&old);
+        }
+      } else {
+        File::create(path)?;
+      }
+      self.kept = Some(path.to_owned());
+    }
+    self.spill()
+  }
+
+  /// The file given to [`Recording::keep`], if any
+  pub fn kept(&self) -> Option<&Path> {
+    self.kept.as_deref()
+  }
+
+  /// Opens the kept file of a loaded recording, checking it
+  /// still holds every frame
+  pub fn reopen(&mut self) -> Result<()> {
+    self.file().map(|_| ())
+  }
+
+  // The file frames are moved to, opened or created the
+  // first time
+  fn file(&mut self) -> Result<&mut File> {
+    if self.file.is_none() {
+      static COUNT: AtomicUsize = AtomicUsize::new(0);
+      let path = self.kept.clone().unwrap_or_else(|| {
+        std::env::temp_dir().join(format!(
+          "fishbowl-{}-{}.rec",
+          std::process::id(),
+          COUNT.fetch_add(1, Ordering::Relaxed)
+        ))
+      });
+      let file = File::options()
+        .read(true)
+        .write(true)
+        .create(self.kept.is_none())
+        .truncate(false)
+        .open(&path)?;
+      if file.metadata()?.len() < self.spilled as u64 {
+        return Err(Error::new(
+          ErrorKind::UnexpectedEof,
+          format!("'{}' is missing recorded frames", path.display()),
+        ));
+      }
+      // A kept file can hold frames added after this copy
+      // of the recording was saved
+      file.set_len(self.spilled as u64)?;
+      self.file = Some((file, path));
+    }
+    Ok(&mut self.file.as_mut().unwrap().0)
+  }
+
+  // Moves everything in memory to the end of the file
+  fn spill(&mut self) -> Result<()> {
+    self.file()?;
+    let (file, _) = self.file.as_mut().unwrap();
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(&self.memory)?;
+    self.spilled += self.memory.len();
+    self.memory.clear();
+    Ok(())
+  }
+
+  /// The circles in frame `frame`, whether the obstacles
+  /// are shown and the time of the frame
+  pub fn frame(&mut self, frame: usize) -> Result<(Vec<Sample>, bool, f32)> {
+    let entry = self.frames[frame];
+    let len = entry.count * SAMPLE_BYTES;
+    let mut read = vec![0; len];
+    let bytes = if entry.offset >= self.spilled {
+      let start = entry.offset - self.spilled;
+      &self.memory[start..start + len]
+    } else {
+      // Frames are only ever spilled whole
+      let file = self.file()?;
+      file.seek(SeekFrom::Start(entry.offset as u64))?;
+      file.read_exact(&mut read)?;
+      &read
+    };
+    let (w, h) = self.area;
+    let coord = |b: &[u8], size: f32| {
+      let t = u16::from_le_bytes([b[0], b[1]]) as f32 / u16::MAX as f32;
+      (t * SPAN - (SPAN - 1.0) * 0.5) * size
+    };
+    let samples = bytes
+      .chunks_exact(SAMPLE_BYTES)
+      .map(|b| Sample {
+        index: u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize,
+        position: Vector2::new(coord(&b[4..6], w), coord(&b[6..8], h)),
+        radius: u16::from_le_bytes([b[8], b[9]]) as f32 / RADIUS_STEPS,
+        rested: u16::from_le_bytes([b[10], b[11]]) as f32 / REST_STEPS,
+      })
+      .collect();
+    Ok((samples, entry.obstacles, entry.time))
+  }
+}
+
+impl Drop for Recording {
+  fn drop(&mut self) {
+    if let Some((_, path)) = self.file.take() {
+      if self.kept.is_none() {
+        let _ = std::fs::remove_file(path);
+      }
+    }
+  }
+}
@@ -0,0 +1,66 @@
+use std::{io::Result, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+  field::ForceField, gravity::GravityTrack, obstacle::Obstacle, timeline::Cue,
+};
+
+/// Extra setup for a run, read from a JSON file with
+/// `--scene`. Everything is optional, e.g.
+///
+/// ```json
+/// {
+///   "obstacles": [
+///     { "type": "peg", "center": [0.5, 0.4], "radius": 0.03 },
+///     { "type": "box", "min": [0.1, 0.6], "max": [0.3, 0.65] }
+///   ],
+///   "remove_obstacles_at": 5.0,
+///   "gravity": {
+///     "keys": [
+///       { "time": 4.0, "angle": 90 },
+///       { "time": 5.0, "angle": 180 },
+///       { "time": 7.0, "angle": 90 }
+///     ],
+///     "shakes": [{ "start": 9.0, "duration": 1.5, "strength": 2 }]
+///   },
+///   "fields": [
+///     {
+///       "type": "vortex", "center": [0.5, 0.5], "reach": 0.4,
+///       "strength": 2, "name": "swirl", "enabled": false
+///     },
+///     { "type": "wind", "angle": 0, "strength": 0.5, "start": 2, "end": 3 }
+///   ],
+///   "timeline": [
+///     { "at": 3.0, "type": "impulse", "speed": 4, "angle": 270 },
+///     { "at": 4.0, "type": "enable_field", "field": "swirl" },
+///     { "at": 6.0, "type": "disable_field", "field": "swirl" },
+///     { "at": 8.0, "type": "pause_emitters" },
+///     { "at": 9.0, "type": "resume_emitters" }
+///   ]
+/// }
+/// ```
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scene {
+  pub obstacles: Vec<Obstacle>,
+  /// Seconds until the obstacles are taken away, so circles
+  /// can settle into the space they took up. They stay for
+  /// the whole run when not set, leaving holes in the image
+  pub remove_obstacles_at: Option<f32>,
+  /// Tilts and shakes, see [`GravityTrack`]
+  pub gravity: GravityTrack,
+  /// Attractors, vortices, wind and drag, see
+  /// [`ForceField`]
+  pub fields: Vec<ForceField>,
+  /// Events run at set times, see
+  /// [`Event`](crate::timeline::Event)
+  pub timeline: Vec<Cue>,
+}
+
+impl Scene {
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&text)?)
+  }
+}
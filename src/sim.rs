@@ -1,33 +1,659 @@
-use std::hash::{Hash, Hasher};
-
-use crate::{helper::*, make_progress};
+use crate::{
+  color::{Fit, Letterbox, Placement, Sampler, Sampling},
+  container::Container,
+  dither::{dither, Site},
+  emitter::{EmitContext, Emitter, TwinNozzle},
+  field::ForceField,
+  gravity::GravityTrack,
+  grid::Grid,
+  helper::*,
+  make_progress,
+  obstacle::Obstacle,
+  palette::{Palette, PaletteSource},
+  record::{Recording, Sample},
+  size::{SizeField, SizePasses},
+  slideshow::Change,
+  state,
+  timeline::{Cue, Event},
+};
 use image::{ImageBuffer, Rgb};
+use indicatif::ProgressBar;
+use rayon::ThreadPool;
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc};
 
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct Circle {
   pub position: Vector2,
   last_position: Vector2,
   pub radius: f32,
   pub color: Color,
   index: usize,
+  // Substeps in a row spent slower than `SLEEP_SPEED`
+  quiet: u16,
+  // Skipped by the solver until something disturbs it
+  asleep: bool,
+  // Seconds spent slower than `REST_SPEED`
+  rested: f32,
+}
+
+impl Circle {
+  /// Order the circle spawned in
+  pub fn index(&self) -> usize {
+    self.index
+  }
+
+  /// Seconds the circle has spent resting, in total
+  pub fn rested(&self) -> f32 {
+    self.rested
+  }
+
+  #[inline]
+  fn wake(&mut self) {
+    self.asleep = false;
+    self.quiet = 0;
+  }
+}
+
+/// Settings for [`Simulation::simulate_image`]. Use
+/// [`SimulationConfig::builder`] to have them checked
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+  pub width: f32,
+  pub height: f32,
+  pub circle_radius: f32,
+  /// Seed for the spawn pattern. The same seed and settings
+  /// always give the same animation. When `None`, one is
+  /// derived from the image with [`Simulation::image_seed`]
+  pub seed: Option<usize>,
+  pub threads: usize,
+  /// Where circles come from. Uses a [`TwinNozzle`] when
+  /// empty
+  pub emitters: Vec<Box<dyn Emitter>>,
+  pub container: Container,
+  pub obstacles: Vec<Obstacle>,
+  /// Seconds until the obstacles are removed, if ever.
+  /// Preprocessing keeps going until after then, so circles
+  /// can settle into the space they took up
+  pub remove_obstacles_at: Option<f32>,
+  /// Picks each circle's radius from where it lands, within
+  /// `radius_range`. Every circle uses `circle_radius` when
+  /// `None`
+  pub size_field: Option<SizeField>,
+  /// Builds the size field from the detail in the image
+  /// when no other is given
+  pub auto_size: bool,
+  pub radius_range: (f32, f32),
+  /// Seconds per step
+  pub timescale: f32,
+  /// Physics substeps per step, more is stiffer but slower
+  pub substeps: usize,
+  /// Downward pull in pixels per second squared. The height
+  /// of the area when `None`
+  pub gravity: Option<f32>,
+  /// Changes to the direction and strength of gravity over
+  /// time, relative to `gravity`
+  pub gravity_track: GravityTrack,
+  /// Extra forces, applied alongside gravity
+  pub fields: Vec<ForceField>,
+  /// Events to run, in time order
+  pub timeline: Vec<Cue>,
+  /// Fraction of an overlap pushed apart every substep,
+  /// lower is softer
+  pub response_mod: f32,
+  /// How much radii wobble, as a fraction of
+  /// `circle_radius`
+  pub radius_variance: f32,
+  /// Fraction of the free area filled with circles
+  pub packing: f32,
+  /// Steps run after the last spawn to let circles settle
+  pub post_process: usize,
+  /// Record a frame every this many steps while
+  /// preprocessing, so rendering can replay it
+  pub record: Option<usize>,
+  /// How circles take their color from the image
+  pub sampling: Sampling,
+  /// Average colors in linear light
+  pub linear_light: bool,
+  /// Colors the circles are limited to
+  pub palette: Option<PaletteSource>,
+  /// How much of each circle's error from the palette is
+  /// spread to its neighbours, between 0 and 1
+  pub dither: f32,
+  /// How the bowl goes from one picture to the next, with
+  /// more than one image
+  pub change: Change,
+  /// Seconds each picture is shown before changing to the
+  /// next
+  pub show: f32,
+  /// How the image is laid over the container
+  pub fit: Fit,
+  /// Where the image sits with [`Fit::Contain`], or the
+  /// part kept with [`Fit::Cover`], as fractions along each
+  /// axis
+  pub align: (f32, f32),
+  /// What circles outside the image get with
+  /// [`Fit::Contain`]
+  pub letterbox: Letterbox,
+  /// File preprocessing saves its progress to every so
+  /// often, and carries on from when it was saved with the
+  /// same images and options. Once done it holds the
+  /// result, so running again skips preprocessing
+  pub checkpoint: Option<PathBuf>,
+}
+
+impl Default for SimulationConfig {
+  fn default() -> Self {
+    Self {
+      width: 512.0,
+      height: 512.0,
+      circle_radius: 8.0,
+      seed: None,
+      threads: 1,
+      emitters: vec![],
+      container: Container::Rect,
+      obstacles: vec![],
+      remove_obstacles_at: None,
+      size_field: None,
+      auto_size: false,
+      radius_range: (4.0, 12.0),
+      timescale: 1.0 / 60.0,
+      substeps: 8,
+      gravity: None,
+      gravity_track: GravityTrack::default(),
+      fields: vec![],
+      timeline: vec![],
+      response_mod: 0.9,
+      radius_variance: 0.1,
+      packing: Simulation::PACKING,
+      post_process: Simulation::POST_PROCESS,
+      record: None,
+      sampling: Sampling::Center,
+      linear_light: false,
+      palette: None,
+      dither: 0.0,
+      change: Change::default(),
+      show: 2.0,
+      fit: Fit::Stretch,
+      align: (0.5, 0.5),
+      letterbox: Letterbox::default(),
+      checkpoint: None,
+    }
+  }
+}
+
+impl SimulationConfig {
+  pub fn builder() -> SimulationConfigBuilder {
+    SimulationConfigBuilder {
+      config: Self::default(),
+    }
+  }
+
+  /// Checks every setting is in range, describing the first
+  /// one that isn't
+  pub fn validate(&self) -> Result<(), String> {
+    let radius_limits = 1.0..=50.0;
+    if !(self.width > 0.0 && self.height > 0.0) {
+      return Err(format!(
+        "Invalid size {}x{}, must be above 0",
+        self.width, self.height
+      ));
+    }
+    for radius in [self.circle_radius, self.radius_range.0, self.radius_range.1]
+    {
+      if !radius_limits.contains(&radius) {
+        return Err(format!(
+          "Invalid radius {}, must be between 1.0 and 50.0 inclusive",
+          radius
+        ));
+      }
+    }
+    if self.radius_range.0 > self.radius_range.1 {
+      return Err(format!(
+        "Minimum radius {} cannot be above the maximum {}",
+        self.radius_range.0, self.radius_range.1
+      ));
+    }
+    if self.threads == 0 {
+      return Err("Thread count cannot be 0".into());
+    }
+    self.container.validate()?;
+    for obstacle in &self.obstacles {
+      obstacle.validate()?;
+    }
+    if let Some(at) = self.remove_obstacles_at {
+      if at.is_nan() || at < 0.0 {
+        return Err(format!(
+          "Invalid time to remove the obstacles {}, cannot be negative",
+          at
+        ));
+      }
+    }
+    if !(self.timescale > 0.0 && self.timescale <= 1.0) {
+      return Err(format!(
+        "Invalid timescale {}, must be above 0 and at most 1",
+        self.timescale
+      ));
+    }
+    if !(1..=64).contains(&self.substeps) {
+      return Err(format!(
+        "Invalid substep count {}, must be between 1 and 64 inclusive",
+        self.substeps
+      ));
+    }
+    if let Some(gravity) = self.gravity {
+      if !gravity.is_finite() {
+        return Err(format!("Invalid gravity {}", gravity));
+      }
+    }
+    for shake in &self.gravity_track.shakes {
+      if !(shake.duration > 0.0 && shake.frequency >= 0.0) {
+        return Err(format!(
+          "Invalid shake at {}s, the duration must be above 0 and the \
+           frequency not negative",
+          shake.start
+        ));
+      }
+    }
+    for field in &self.fields {
+      field.validate()?;
+    }
+    for cue in &self.timeline {
+      if let Event::EnableField { field } | Event::DisableField { field } =
+        &cue.event
+      {
+        if !self.fields.iter().any(|f| f.name.as_ref() == Some(field)) {
+          return Err(format!(
+            "The timeline refers to field '{}' at {}s, but no field has that \
+             name",
+            field, cue.at
+          ));
+        }
+      }
+    }
+    if !(self.response_mod > 0.0 && self.response_mod <= 1.0) {
+      return Err(format!(
+        "Invalid collision response {}, must be above 0 and at most 1",
+        self.response_mod
+      ));
+    }
+    if !(0.0..=0.5).contains(&self.radius_variance) {
+      return Err(format!(
+        "Invalid radius variance {}, must be between 0 and 0.5 inclusive",
+        self.radius_variance
+      ));
+    }
+    if !(self.packing > 0.0 && self.packing <= 1.0) {
+      return Err(format!(
+        "Invalid packing {}, must be above 0 and at most 1",
+        self.packing
+      ));
+    }
+    if !(0.0..=1.0).contains(&self.dither) {
+      return Err(format!(
+        "Invalid dither strength {}, must be between 0 and 1 inclusive",
+        self.dither
+      ));
+    }
+    if self.show.is_nan() || self.show < 0.0 {
+      return Err(format!(
+        "Invalid time to show each picture {}, cannot be negative",
+        self.show
+      ));
+    }
+    if !(0.0..=1.0).contains(&self.align.0)
+      || !(0.0..=1.0).contains(&self.align.1)
+    {
+      return Err(format!(
+        "Invalid alignment {},{}, must be between 0 and 1 inclusive",
+        self.align.0, self.align.1
+      ));
+    }
+    if self.record == Some(0) {
+      return Err("Steps between recorded frames cannot be 0".into());
+    }
+    Ok(())
+  }
+}
+
+/// Builds a [`SimulationConfig`], starting from the
+/// defaults and checking the result
+pub struct SimulationConfigBuilder {
+  config: SimulationConfig,
+}
+
+impl SimulationConfigBuilder {
+  pub fn size(mut self, width: f32, height: f32) -> Self {
+    self.config.width = width;
+    self.config.height = height;
+    self
+  }
+
+  pub fn circle_radius(mut self, radius: f32) -> Self {
+    self.config.circle_radius = radius;
+    self
+  }
+
+  pub fn seed(mut self, seed: usize) -> Self {
+    self.config.seed = Some(seed);
+    self
+  }
+
+  pub fn threads(mut self, threads: usize) -> Self {
+    self.config.threads = threads;
+    self
+  }
+
+  pub fn emitters(mut self, emitters: Vec<Box<dyn Emitter>>) -> Self {
+    self.config.emitters = emitters;
+    self
+  }
+
+  pub fn container(mut self, container: Container) -> Self {
+    self.config.container = container;
+    self
+  }
+
+  pub fn obstacles(
+    mut self,
+    obstacles: Vec<Obstacle>,
+    remove_at: Option<f32>,
+  ) -> Self {
+    self.config.obstacles = obstacles;
+    self.config.remove_obstacles_at = remove_at;
+    self
+  }
+
+  pub fn size_field(mut self, field: SizeField) -> Self {
+    self.config.size_field = Some(field);
+    self
+  }
+
+  pub fn auto_size(mut self, auto_size: bool) -> Self {
+    self.config.auto_size = auto_size;
+    self
+  }
+
+  pub fn radius_range(mut self, min: f32, max: f32) -> Self {
+    self.config.radius_range = (min, max);
+    self
+  }
+
+  pub fn timescale(mut self, timescale: f32) -> Self {
+    self.config.timescale = timescale;
+    self
+  }
+
+  pub fn substeps(mut self, substeps: usize) -> Self {
+    self.config.substeps = substeps;
+    self
+  }
+
+  pub fn gravity(mut self, gravity: f32) -> Self {
+    self.config.gravity = Some(gravity);
+    self
+  }
+
+  pub fn gravity_track(mut self, mut track: GravityTrack) -> Self {
+    track.sort();
+    self.config.gravity_track = track;
+    self
+  }
+
+  pub fn fields(mut self, fields: Vec<ForceField>) -> Self {
+    self.config.fields = fields;
+    self
+  }
+
+  pub fn timeline(mut self, mut timeline: Vec<Cue>) -> Self {
+    crate::timeline::sort(&mut timeline);
+    self.config.timeline = timeline;
+    self
+  }
+
+  pub fn response_mod(mut self, response_mod: f32) -> Self {
+    self.config.response_mod = response_mod;
+    self
+  }
+
+  pub fn radius_variance(mut self, variance: f32) -> Self {
+    self.config.radius_variance = variance;
+    self
+  }
+
+  pub fn packing(mut self, packing: f32) -> Self {
+    self.config.packing = packing;
+    self
+  }
+
+  pub fn post_process(mut self, steps: usize) -> Self {
+    self.config.post_process = steps;
+    self
+  }
+
+  pub fn record(mut self, every: usize) -> Self {
+    self.config.record = Some(every);
+    self
+  }
+
+  pub fn sampling(mut self, sampling: Sampling, linear_light: bool) -> Self {
+    self.config.sampling = sampling;
+    self.config.linear_light = linear_light;
+    self
+  }
+
+  pub fn palette(mut self, palette: PaletteSource) -> Self {
+    self.config.palette = Some(palette);
+    self
+  }
+
+  pub fn dither(mut self, strength: f32) -> Self {
+    self.config.dither = strength;
+    self
+  }
+
+  pub fn slideshow(mut self, change: Change, show: f32) -> Self {
+    self.config.change = change;
+    self.config.show = show;
+    self
+  }
+
+  pub fn fit(
+    mut self,
+    fit: Fit,
+    align: (f32, f32),
+    letterbox: Letterbox,
+  ) -> Self {
+    self.config.fit = fit;
+    self.config.align = align;
+    self.config.letterbox = letterbox;
+    self
+  }
+
+  pub fn checkpoint(mut self, path: PathBuf) -> Self {
+    self.config.checkpoint = Some(path);
+    self
+  }
+
+  pub fn build(self) -> Result<SimulationConfig, String> {
+    self.config.validate()?;
+    Ok(self.config)
+  }
+}
+
+// Where `pos` falls on the size field, as fractions of
+// its size. The field is stretched over the container's
+// `bounds`
+#[inline]
+fn image_coords(bounds: (Vector2, Vector2), pos: Vector2) -> (f32, f32) {
+  let size = bounds.1 - bounds.0;
+  let pos = pos - bounds.0;
+  (
+    (pos.x / size.x).clamp(0.0, 1.0),
+    (pos.y / size.y).clamp(0.0, 1.0),
+  )
+}
+
+// Colors of every circle in a picture after the first, and
+// when the circles change over to them
+#[derive(Clone, Serialize, Deserialize)]
+struct Picture {
+  // Seconds since the start the change begins and ends at
+  from: f32,
+  until: f32,
+  colors: Vec<Color>,
+  hidden: Vec<bool>,
+}
+
+/// Where a replay stopped matching the run it replays
+#[derive(Debug)]
+pub struct Divergence {
+  /// Step after which the runs differ
+  pub step: usize,
+  /// Spawn index of the first circle that differs, unknown
+  /// when only the final checksum was compared
+  pub circle: Option<usize>,
+}
+
+impl std::fmt::Display for Divergence {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.circle {
+      Some(circle) => write!(
+        f,
+        "The replay diverged after step {}, starting with circle {}",
+        self.step, circle
+      ),
+      None => write!(
+        f,
+        "The replay ended on step {} in a different state than \
+         preprocessing",
+        self.step
+      ),
+    }
+  }
+}
+
+// Spawn index of the first circle that is missing from or
+// different in one of the sets of circles
+fn first_difference(a: &[Circle], b: &[Circle]) -> usize {
+  let by_index = |circles: &[Circle]| {
+    let len = circles.iter().map(|c| c.index + 1).max().unwrap_or(0);
+    let mut by_index = vec![None; len];
+    for c in circles {
+      by_index[c.index] = Some((
+        c.position.x.to_bits(),
+        c.position.y.to_bits(),
+        c.radius.to_bits(),
+      ));
+    }
+    by_index
+  };
+  let (a, b) = (by_index(a), by_index(b));
+  (0..a.len().max(b.len()))
+    .find(|&i| a.get(i).copied().flatten() != b.get(i).copied().flatten())
+    .unwrap_or(0)
+}
+
+// What preprocessing keeps for [`Simulation::verify`]: the
+// checksum after every step, and the circles after step
+// `capture` when set
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Trace {
+  checksums: Vec<u64>,
+  capture: Option<usize>,
+  circles: Vec<Circle>,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Simulation {
   pub circles: Vec<Circle>,
   pub colors: Vec<Color>,
   pub max_circles: usize,
   pub clock: usize,
   pub substeps: usize,
+  pub threads: usize,
+  pub emitters: Vec<Box<dyn Emitter>>,
   rand_seed: usize,
   timescale: f32,
   circle_radius: f32,
   radius_variance: f32,
   area_size: (f32, f32),
+  container: Container,
+  obstacles: Vec<Obstacle>,
+  remove_obstacles_at: Option<f32>,
+  // Radius of each spawn index, if not all the same
+  radii: Vec<f32>,
+  // Seconds since the start each spawn index spawned at
+  born: Vec<f32>,
+  size_field: Option<SizeField>,
+  radius_range: (f32, f32),
   gravity: f32,
+  gravity_track: GravityTrack,
+  fields: Vec<ForceField>,
+  timeline: Vec<Cue>,
+  // Index of the next cue to run
+  next_cue: usize,
+  floor_open: bool,
+  emitters_paused: bool,
+  // Circles spawned so far, including any that have left
+  spawned: usize,
+  // Index, last position and size of circles that left, so they
+  // can still be colored
+  drained: Vec<(usize, Vector2, f32)>,
+  // Index and size of circles that fell out since the last
+  // refill
+  fallen: Vec<(usize, f32)>,
+  // Circles waiting to be sent back in, first one last
+  refill: Vec<(usize, f32)>,
+  // Circles left out of the frames, by spawn index
+  hidden: Vec<bool>,
+  // Pictures shown after the one in `colors`
+  pictures: Vec<Picture>,
+  // Checksum the preprocessing pass ended on, which
+  // replaying has to reach too
+  expected: Option<u64>,
+  // Only kept while verifying
+  trace: Option<Trace>,
+  // Colors the circles were snapped to, if limited
+  palette: Vec<Color>,
   response_mod: f32,
+  packing: f32,
+  #[serde(skip)]
+  grid: Grid,
+  // Threads the work is spread over, started when first
+  // needed rather than every substep
+  #[serde(skip)]
+  pool: Option<Arc<ThreadPool>>,
+  #[serde(skip)]
+  scratch: Vec<Circle>,
 }
 
 impl Simulation {
   pub const POST_PROCESS: usize = 120;
+  /// Default fraction of the free area filled with circles
+  pub const PACKING: f32 = 0.9;
+  // Fraction of speed kept when bouncing off an obstacle
+  const BOUNCE: f32 = 0.5;
+  // Fewest circles worth handing to another thread
+  const PARALLEL_GRAIN: usize = 256;
+  // How quickly circles take on the size of where they are
+  const SIZE_RATE: f32 = 0.05;
+  // Pixels per substep below which a circle counts as
+  // resting
+  const SLEEP_SPEED: f32 = 0.01;
+  // Substeps every circle touching a resting circle has to
+  // rest for before they all fall asleep together
+  const SLEEP_DELAY: u16 = 64;
+  // Gap up to which circles count as touching
+  const CONTACT_GAP: f32 = 0.5;
+  // Pixels per substep below which a circle counts as
+  // resting, for coloring
+  const REST_SPEED: f32 = 0.05;
+  // Number of different starting phases for the emitters
+  const PHASES: u64 = 1 << 16;
+  // Seconds without a new circle, once nothing else is due
+  // to change, after which the emitters count as stuck
+  const STALL_SECONDS: f32 = 20.0;
 
   pub fn new(
     width: f32,
@@ -35,204 +661,1005 @@ impl Simulation {
     circle_radius: f32,
     rand_seed: usize,
   ) -> Self {
+    let defaults = SimulationConfig::default();
     let area = width * height;
     let circle_area = circle_radius.powi(2) * std::f32::consts::PI;
-    let approx_max = ((area / circle_area).round() * 0.9) as usize;
+    let approx_max = ((area / circle_area).round() * defaults.packing) as usize;
     Self {
       circles: Vec::with_capacity(approx_max),
       max_circles: approx_max,
-      timescale: 1.0 / 60.0,
-      substeps: 8,
+      timescale: defaults.timescale,
+      substeps: defaults.substeps,
+      threads: defaults.threads,
+      emitters: vec![Box::new(TwinNozzle::default())],
       colors: vec![Color(255, 255, 255); approx_max],
-      clock: rand_seed,
+      clock: 0,
       rand_seed,
       circle_radius,
-      radius_variance: circle_radius * 0.1,
+      radius_variance: circle_radius * defaults.radius_variance,
       area_size: (width, height),
+      container: Container::Rect,
+      obstacles: vec![],
+      remove_obstacles_at: None,
+      radii: vec![],
+      born: vec![],
+      size_field: None,
+      radius_range: (circle_radius, circle_radius),
       gravity: height,
-      response_mod: 0.9,
+      gravity_track: GravityTrack::default(),
+      fields: vec![],
+      timeline: vec![],
+      next_cue: 0,
+      expected: None,
+      trace: None,
+      palette: vec![],
+      floor_open: false,
+      emitters_paused: false,
+      spawned: 0,
+      drained: vec![],
+      fallen: vec![],
+      refill: vec![],
+      hidden: vec![],
+      pictures: vec![],
+      response_mod: defaults.response_mod,
+      packing: defaults.packing,
+      grid: Grid::new(),
+      pool: None,
+      scratch: Vec::with_capacity(approx_max),
     }
   }
 
   #[inline]
   async fn assign_colors_from_image(
     &mut self,
-    img: ImageBuffer<Rgb<u8>, Vec<u8>>,
+    img: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    config: &SimulationConfig,
   ) {
     let (width, height) = (img.width() as f32 - 1.0, img.height() as f32 - 1.0);
-    for (pos, index) in self.circles.iter().map(|c| (c.position, c.index)) {
-      let img_x =
-        ((pos.x / self.area_size.0).clamp(0.0, 1.0) * width).round() as u32;
-      let img_y =
-        ((pos.y / self.area_size.1).clamp(0.0, 1.0) * height).round() as u32;
-      let pixel = img.get_pixel(img_x, img_y);
-      let color = Color(pixel[0], pixel[1], pixel[2]);
-
+    // The image is laid over the container rather than the
+    // whole area, so none of it is wasted outside
+    let bounds = self.container.bounds(self.area_size);
+    let placement =
+      Placement::new(config.fit, config.align, bounds, img.dimensions());
+    let scale = placement.scale(img.dimensions());
+    let sampler = Sampler::new(img, config.sampling, config.linear_light);
+    self.hidden.resize(self.colors.len(), false);
+    let circles = self.circles.iter().map(|c| (c.index, c.position, c.radius));
+    for (index, pos, radius) in circles.chain(self.drained.iter().copied()) {
+      let (color, hidden) = match (placement.coords(pos), config.letterbox) {
+        (Some((u, v)), _) => (
+          sampler.sample(
+            (u * width, v * height),
+            (radius * scale.0, radius * scale.1),
+          ),
+          false,
+        ),
+        (None, Letterbox::Solid(color)) => (color, false),
+        (None, Letterbox::Skip) => (Color(0, 0, 0), true),
+      };
       self.colors[index] = color;
+      self.hidden[index] = hidden;
     }
   }
 
+  /// Fallback seed used when none is given, a stable hash
+  /// of the image
+  pub fn image_seed(img: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> usize {
+    let hash = fnv1a(FNV_OFFSET, &img.width().to_le_bytes());
+    let hash = fnv1a(hash, &img.height().to_le_bytes());
+    (fnv1a(hash, img.as_raw()) % 1204) as usize
+  }
+
+  pub fn seed(&self) -> usize {
+    self.rand_seed
+  }
+
+  // Where in their sweeps the emitters start, picked from
+  // the seed. Kept small so the emitters' angles don't lose
+  // precision
+  fn phase(&self) -> usize {
+    (mix(self.rand_seed as u64) % Self::PHASES) as usize
+  }
+
+  /// Simulates the image forming and colors the circles by
+  /// where they end up. Returns a simulation ready to
+  /// replay it, the clock value it ends on and the number
+  /// of circles. When recording, the simulation is
+  /// returned where it ended instead, along with the
+  /// recording
   #[inline]
   pub async fn simulate_image(
-    width: f32,
-    height: f32,
-    circle_radius: f32,
+    config: &SimulationConfig,
     img: ImageBuffer<Rgb<u8>, Vec<u8>>,
-  ) -> (Self, usize, usize) {
-    let image_hash = ({
-      let mut s = std::hash::DefaultHasher::new();
-      img.hash(&mut s);
-      s.finish()
-    } % 1204) as usize;
-    let mut sim = Simulation::new(width, height, circle_radius, image_hash);
+  ) -> (Self, usize, usize, Option<Recording>) {
+    Self::simulate_images(config, vec![img]).await
+  }
+
+  /// Like [`Self::simulate_image`], but once a picture has
+  /// formed and been shown for `config.show` seconds the
+  /// bowl goes through `config.change`, and the same
+  /// circles form the next image. Sizes only fit the
+  /// first image
+  pub async fn simulate_images(
+    config: &SimulationConfig,
+    images: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+  ) -> (Self, usize, usize, Option<Recording>) {
+    Self::preprocess(config, images, None).await
+  }
+
+  // Simulates the images, keeping `trace` for every pass
+  // when given. The returned simulation holds the trace of
+  // the pass that was kept. With `config.checkpoint` the
+  // progress is saved every so often, and picked up from
+  // when it was saved with the same images and options
+  async fn preprocess(
+    config: &SimulationConfig,
+    images: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+    trace: Option<Trace>,
+  ) -> (Self, usize, usize, Option<Recording>) {
+    let Some(path) = &config.checkpoint else {
+      let mut pre = Preprocessing::traced(config, &images, trace);
+      pre.run(config, &images, usize::MAX).await;
+      return pre.finish(config);
+    };
+    let fingerprint = Preprocessing::fingerprint(config, &images);
+    let resumed = match state::load_checkpoint(path) {
+      Ok(pre) if pre.fingerprint == fingerprint => Some(pre),
+      Ok(_) => {
+        eprintln!(
+          "'{}' was saved with other images or options, starting over",
+          path.display()
+        );
+        None
+      },
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+      Err(e) => {
+        eprintln!("Could not resume from '{}', starting over:", path.display());
+        eprintln!("{}", e);
+        None
+      },
+    };
+    let mut pre =
+      resumed.unwrap_or_else(|| Preprocessing::traced(config, &images, trace));
+    loop {
+      let done = pre.run(config, &images, Preprocessing::STEPS).await;
+      if let Err(e) = state::save_checkpoint(path, &mut pre) {
+        eprintln!("Could not save progress to '{}':", path.display());
+        eprintln!("{}", e);
+      }
+      if done {
+        return pre.finish(config);
+      }
+    }
+  }
+
+  // Every circle that has spawned and isn't left out, with
+  // where it is or where it fell out
+  fn sites(&self) -> Vec<Site> {
+    let circles = self.circles.iter().map(|c| (c.index, c.position, c.radius));
+    let hidden = |s: &Site| self.hidden.get(s.0).copied().unwrap_or(false);
+    circles
+      .chain(self.drained.iter().copied())
+      .filter(|s| !hidden(s))
+      .collect()
+  }
+
+  /// Whether the circle with spawn index `index` is left
+  /// out of the frames at `time` seconds since the start.
+  /// Once a change starts the next picture decides
+  pub fn hidden_at(&self, index: usize, time: f32) -> bool {
+    let mut hidden = self.hidden.get(index).copied().unwrap_or(false);
+    for picture in &self.pictures {
+      if time <= picture.from {
+        break;
+      }
+      hidden = picture.hidden.get(index).copied().unwrap_or(hidden);
+    }
+    hidden
+  }
+
+  /// Color of the circle with spawn index `index` at `time`
+  /// seconds since the start, blending from one picture to
+  /// the next while the bowl changes over
+  pub fn color_at(&self, index: usize, time: f32) -> Color {
+    let white = Color(255, 255, 255);
+    let mut color = self.colors.get(index).copied().unwrap_or(white);
+    for picture in &self.pictures {
+      if time <= picture.from {
+        break;
+      }
+      let next = picture.colors.get(index).copied().unwrap_or(color);
+      let t = ((time - picture.from) / (picture.until - picture.from)).min(1.0);
+      let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round();
+      color = Color(
+        mix(color.0, next.0) as u8,
+        mix(color.1, next.1) as u8,
+        mix(color.2, next.2) as u8,
+      );
+    }
+    color
+  }
+
+  /// Runs preprocessing keeping a checksum of every step,
+  /// then replays it, checking the replay matches
+  /// preprocessing after each step and ends on the same
+  /// checksum. On the first step that differs,
+  /// preprocessing runs again to find the first circle
+  /// that differs there. Returns the number of steps
+  /// checked
+  pub async fn verify(
+    config: &SimulationConfig,
+    images: Vec<ImageBuffer<Rgb<u8>, Vec<u8>>>,
+  ) -> Result<usize, Divergence> {
+    let mut config = config.clone();
+    config.record = None;
+    config.checkpoint = None;
+    let (mut replay, it, ..) =
+      Self::preprocess(&config, images.clone(), Some(Trace::default())).await;
+    let checksums = replay.trace.take().unwrap_or_default().checksums;
     let progress = make_progress(
-      "Preprocessing",
-      (sim.max_circles + Self::POST_PROCESS) as u64,
+      "Verifying    ",
+      ((it - replay.clock) / replay.substeps) as u64,
     );
-    while sim.circles() < sim.max_circles {
-      sim.step().await;
-      progress.inc(2);
-    }
-    for _ in 0..Self::POST_PROCESS {
-      sim.step().await;
+    for checksum in checksums {
+      replay.step().await;
       progress.inc(1);
+      if replay.checksum() != checksum {
+        progress.finish();
+        let step = replay.tick();
+        let trace = Trace {
+          capture: Some(step),
+          ..Trace::default()
+        };
+        let (mut again, ..) =
+          Self::preprocess(&config, images, Some(trace)).await;
+        let circles = again.trace.take().unwrap_or_default().circles;
+        return Err(Divergence {
+          step,
+          circle: Some(first_difference(&replay.circles, &circles)),
+        });
+      }
     }
     progress.finish();
-    let total_iterations = sim.clock;
-    let max_circles = sim.circles.len();
-    sim.assign_colors_from_image(img).await;
-    sim.circles.clear();
-    sim.clock = sim.rand_seed;
-    (sim, total_iterations, max_circles)
+    if replay.clock != it || replay.expected != Some(replay.checksum()) {
+      return Err(Divergence {
+        step: replay.tick(),
+        circle: None,
+      });
+    }
+    Ok(replay.tick())
+  }
+
+  /// Stable hash of the clock and every circle's position
+  /// and size, in spawn order
+  pub fn checksum(&self) -> u64 {
+    let mut circles: Vec<&Circle> = self.circles.iter().collect();
+    circles.sort_by_key(|c| c.index);
+    let hash = fnv1a(FNV_OFFSET, &self.clock.to_le_bytes());
+    circles.iter().fold(hash, |hash, c| {
+      let hash = fnv1a(hash, &c.index.to_le_bytes());
+      let hash = fnv1a(hash, &c.position.x.to_le_bytes());
+      let hash = fnv1a(hash, &c.position.y.to_le_bytes());
+      fnv1a(hash, &c.radius.to_le_bytes())
+    })
+  }
+
+  /// Radius the circle with spawn index `index` spawns
+  /// with, when sizes were picked ahead of time
+  pub fn spawn_radius(&self, index: usize) -> Option<f32> {
+    self.radii.get(index).copied()
+  }
+
+  /// Seconds since the start the circle with spawn index
+  /// `index` spawned at
+  pub fn born(&self, index: usize) -> f32 {
+    self.born.get(index).copied().unwrap_or(0.0)
+  }
+
+  /// Colors the circles are limited to, if any
+  pub fn palette(&self) -> Option<Palette> {
+    (!self.palette.is_empty()).then(|| Palette::new(self.palette.clone()))
+  }
+
+  /// Checksum a replay has to reach by the end, if known
+  pub fn expected_checksum(&self) -> Option<u64> {
+    self.expected
+  }
+
+  // A simulation ready to start, with `radii` for each spawn
+  // index when given
+  fn setup(config: &SimulationConfig, seed: usize, radii: Vec<f32>) -> Self {
+    let mut sim =
+      Simulation::new(config.width, config.height, config.circle_radius, seed);
+    sim.threads = config.threads;
+    sim.timescale = config.timescale;
+    sim.substeps = config.substeps;
+    sim.gravity = config.gravity.unwrap_or(config.height);
+    sim.gravity_track = config.gravity_track.clone();
+    sim.fields = config.fields.clone();
+    sim.response_mod = config.response_mod;
+    sim.radius_variance = config.radius_variance * config.circle_radius;
+    sim.packing = config.packing;
+    sim.radii = radii;
+    sim.size_field = config.size_field.clone();
+    sim.radius_range = config.radius_range;
+    sim.set_container(config.container.clone());
+    sim.set_obstacles(config.obstacles.clone(), config.remove_obstacles_at);
+    if !config.emitters.is_empty() {
+      sim.emitters = config.emitters.clone();
+    }
+    sim.timeline = config.timeline.clone();
+    sim
+  }
+
+  // Whether circles that fell out are still to be sent back
+  // in, or will be
+  fn refilling(&self) -> bool {
+    let pending = self.timeline[self.next_cue..]
+      .iter()
+      .any(|c| matches!(c.event, Event::Refill));
+    pending || !self.refill.is_empty()
+  }
+
+  pub fn container(&self) -> &Container {
+    &self.container
+  }
+
+  /// Replaces the container, and resizes the circle count
+  /// to fill it
+  pub fn set_container(&mut self, container: Container) {
+    self.container = container;
+    self.fit_circle_count();
+    self.wake_all();
+  }
+
+  pub fn obstacles(&self) -> &[Obstacle] {
+    &self.obstacles
+  }
+
+  /// Replaces the obstacles, which are taken out after
+  /// `remove_at` seconds if given. Permanent obstacles take
+  /// up room so fewer circles are spawned
+  pub fn set_obstacles(
+    &mut self,
+    obstacles: Vec<Obstacle>,
+    remove_at: Option<f32>,
+  ) {
+    self.obstacles = obstacles;
+    self.remove_obstacles_at = remove_at;
+    self.fit_circle_count();
+    self.wake_all();
+  }
+
+  /// Whether the obstacles are still in place
+  pub fn obstacles_active(&self) -> bool {
+    !self.obstacles.is_empty()
+      && self.remove_obstacles_at.is_none_or(|at| self.time() < at)
+  }
+
+  // Sets the number of circles to fill the free space
+  fn fit_circle_count(&mut self) {
+    let mut area = self.container.area(self.area_size);
+    if self.remove_obstacles_at.is_none() {
+      area -= self
+        .obstacles
+        .iter()
+        .map(|o| o.area(self.area_size))
+        .sum::<f32>();
+    }
+    let circle_area = if self.radii.is_empty() {
+      self.circle_radius.powi(2) * std::f32::consts::PI
+    } else {
+      self
+        .radii
+        .iter()
+        .map(|r| r.powi(2) * std::f32::consts::PI)
+        .sum::<f32>()
+        / self.radii.len() as f32
+    };
+    self.max_circles =
+      ((area.max(0.0) / circle_area).round() * self.packing) as usize;
+    self.colors.resize(self.max_circles, Color(255, 255, 255));
+    if !self.radii.is_empty() {
+      // Extra circles get the average size
+      let average = (circle_area / std::f32::consts::PI).sqrt();
+      self.radii.resize(self.max_circles, average);
+    }
+  }
+
+  pub fn area_size(&self) -> (f32, f32) {
+    self.area_size
   }
 
   #[inline]
   pub fn add_circle(&mut self, position: Vector2, velocity: Vector2) {
+    let radius = self.radii.get(self.spawned).copied().unwrap_or_else(|| {
+      let wobble = unit(self.rand_seed as u64 ^ mix(self.spawned as u64));
+      self.circle_radius + (wobble * 2.0 - 1.0) * self.radius_variance
+    });
+    self.place_circle(self.spawned, radius, position, velocity);
+    self.spawned += 1;
+  }
+
+  // Puts the circle with spawn index `index` in the bowl,
+  // whether it's new or coming back
+  fn place_circle(
+    &mut self,
+    index: usize,
+    radius: f32,
+    position: Vector2,
+    velocity: Vector2,
+  ) {
     self.circles.push(Circle {
       position,
       last_position: position - velocity,
-      radius: self.circle_radius
-        + (self.clock as f32).sin() * self.radius_variance,
-      color: self.colors[self.circles.len()],
-      index: self.circles.len(),
-    })
+      radius,
+      color: self
+        .colors
+        .get(index)
+        .copied()
+        .unwrap_or(Color(255, 255, 255)),
+      index,
+      quiet: 0,
+      asleep: false,
+      rested: 0.0,
+    });
+    let time = self.time();
+    match self.born.get_mut(index) {
+      Some(born) => *born = time,
+      None => self.born.push(time),
+    }
   }
 
+  // Steps since the simulation started
   #[inline]
-  pub fn launch(&mut self) {
-    let time = self.clock as f32 / (10.0 * std::f32::consts::PI);
-    let halfwidth = self.area_size.0 / 2.0;
-    let x = ((halfwidth - self.circle_radius * 2.0) * time.cos().abs())
-      + self.circle_radius;
-    self.add_circle(
-      Vector2::new(x, self.circle_radius),
-      Vector2::new(time.cos(), time.sin().abs()),
-    )
+  fn tick(&self) -> usize {
+    self.clock / self.substeps
+  }
+
+  /// Seconds of simulated time since the start
+  #[inline]
+  pub fn time(&self) -> f32 {
+    self.clock as f32 * self.timescale / self.substeps as f32
+  }
+
+  /// Seconds until the forces stop changing or the
+  /// obstacles are taken out. Fields that last forever
+  /// don't count
+  pub fn last_change(&self) -> f32 {
+    let removal = self
+      .remove_obstacles_at
+      .filter(|_| !self.obstacles.is_empty());
+    self
+      .fields
+      .iter()
+      .filter_map(|f| f.end)
+      .chain(self.timeline.last().map(|c| c.at))
+      .chain(removal)
+      .fold(self.gravity_track.end(), f32::max)
+  }
+
+  /// True when no emitter will spawn anything anymore
+  pub fn emitters_finished(&self) -> bool {
+    let tick = self.tick();
+    let resumes = self.timeline[self.next_cue..]
+      .iter()
+      .any(|c| matches!(c.event, Event::ResumeEmitters));
+    (self.emitters_paused && !resumes)
+      || self.emitters.iter().all(|e| e.finished(tick))
+  }
+
+  // Runs the cues that are due
+  fn run_timeline(&mut self) {
+    let time = self.time();
+    while let Some(cue) = self.timeline.get(self.next_cue) {
+      if cue.at > time {
+        break;
+      }
+      let event = cue.event.clone();
+      self.next_cue += 1;
+      self.apply(event);
+    }
   }
 
-  pub fn launch2(&mut self) {
-    let time = self.clock as f32 / (10.0 * std::f32::consts::PI);
-    let halfwidth = self.area_size.0 / 2.0;
-    let x = ((halfwidth - self.circle_radius * 2.0) * time.sin().abs())
-      + self.circle_radius;
-    let x = x + halfwidth;
-    self.add_circle(
-      Vector2::new(x, self.circle_radius),
-      Vector2::new(time.cos(), time.sin().abs()),
-    )
+  /// Runs an event right away, outside of the timeline
+  pub fn apply(&mut self, event: Event) {
+    self.wake_all();
+    match event {
+      Event::Impulse {
+        speed,
+        angle,
+        center,
+      } => {
+        let area = self.area_size;
+        let center = center.map(|c| Vector2::new(c.0 * area.0, c.1 * area.1));
+        let along = crate::emitter::direction(angle);
+        self.for_each_circle(|c| {
+          let direction = match center {
+            Some(center) => {
+              let offset = c.position - center;
+              let length = offset.length2().sqrt();
+              if length > 0.0 {
+                offset * (1.0 / length)
+              } else {
+                along
+              }
+            },
+            None => along,
+          };
+          c.last_position -= direction * speed;
+        });
+      },
+      Event::OpenFloor => self.floor_open = true,
+      Event::CloseFloor => self.floor_open = false,
+      Event::Refill => {
+        self.floor_open = false;
+        // Popped from the end, so circles come back in the
+        // order they fell, after any still waiting
+        let mut fallen = std::mem::take(&mut self.fallen);
+        fallen.reverse();
+        fallen.append(&mut self.refill);
+        self.refill = fallen;
+      },
+      Event::EnableField { field } => self.set_field_enabled(&field, true),
+      Event::DisableField { field } => self.set_field_enabled(&field, false),
+      Event::PauseEmitters => self.emitters_paused = true,
+      Event::ResumeEmitters => self.emitters_paused = false,
+    }
   }
 
-  // Insertion sort
   #[inline]
-  async fn sort(&mut self) {
-    if self.circles.len() == 1 {
+  fn emit(&mut self) {
+    if !self.refill.is_empty() && self.emitters_finished() {
+      self.drop_refill();
+    }
+    if self.emitters_paused {
       return;
     }
-    for i in 1..self.circles.len() {
-      let mut j = i;
-      while j > 0 && self.circles[j - 1].position.x > self.circles[j].position.x
-      {
-        self.circles.swap(j - 1, j);
-        j -= 1;
+    let ctx = EmitContext {
+      tick: self.tick(),
+      clock: self.clock + self.phase(),
+      area: self.area_size,
+      radius: self.circle_radius,
+    };
+    let mut spawns = vec![];
+    for emitter in &self.emitters {
+      emitter.emit(&ctx, &mut spawns);
+    }
+    for spawn in spawns {
+      // Circles coming back go first
+      if let Some((index, radius)) = self.refill.pop() {
+        self.drained.retain(|d| d.0 != index);
+        self.place_circle(index, radius, spawn.position, spawn.velocity);
+      } else if self.spawned < self.max_circles {
+        self.add_circle(spawn.position, spawn.velocity);
+      } else {
+        break;
       }
     }
   }
 
+  // With no emitters left to send them back through, drops
+  // the next circle coming back in along the top of the
+  // container, in the first free spot from one picked by
+  // its index
+  fn drop_refill(&mut self) {
+    let Some(&(index, radius)) = self.refill.last() else {
+      return;
+    };
+    let area = self.area_size;
+    let (min, max) = self.container.bounds(area);
+    let slots = ((max.x - min.x) / (radius * 2.0)).max(1.0) as usize;
+    let start = (mix(index as u64) % slots as u64) as usize;
+    let free = (0..slots)
+      .map(|i| {
+        let slot = (start + i) % slots;
+        let x = min.x + (slot as f32 + 0.5) * radius * 2.0;
+        let p = Vector2::new(x, min.y + radius);
+        self.container.constrain(area, p, radius)
+      })
+      .find(|&p| {
+        self
+          .circles
+          .iter()
+          .all(|c| (c.position - p).length2() >= (c.radius + radius).powi(2))
+      });
+    if let Some(position) = free {
+      self.refill.pop();
+      self.drained.retain(|d| d.0 != index);
+      self.place_circle(index, radius, position, Vector2::new(0.0, 0.0));
+    }
+  }
+
+  // Buckets the circles into grid cells at least one
+  // diameter wide, so collisions only happen between
+  // neighbouring cells
   #[inline]
-  async fn integrate(&mut self) {
+  fn sort(&mut self) {
+    // Floored so an empty simulation doesn't get a
+    // degenerate grid
+    let max_radius = self
+      .circles
+      .iter()
+      .map(|c| c.radius)
+      .fold(self.circle_radius * 0.5, f32::max);
+    self.grid.build(
+      &mut self.circles,
+      &mut self.scratch,
+      max_radius * 2.0,
+      self.area_size,
+      |c| c.position,
+    );
+  }
+
+  // Number of threads worth using for `work` circles,
+  // starting them if they haven't been yet
+  #[inline]
+  fn workers(&mut self, work: usize) -> usize {
+    let workers = self.threads.min(work / Self::PARALLEL_GRAIN).max(1);
+    let started = self.pool.as_ref().map(|p| p.current_num_threads());
+    if workers > 1 && started != Some(self.threads) {
+      // Without a pool the work is done on this thread
+      self.pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(self.threads)
+        .build()
+        .ok()
+        .map(Arc::new);
+    }
+    workers
+  }
+
+  // Runs `f` on every circle, in parallel when there are
+  // enough of them
+  #[inline]
+  fn for_each_circle(&mut self, f: impl Fn(&mut Circle) + Sync) {
+    let workers = self.workers(self.circles.len());
+    let chunk = self.circles.len().div_ceil(workers).max(1);
+    let chunks = self.circles.chunks_mut(chunk).collect();
+    par_each(
+      self.pool.as_deref(),
+      workers,
+      chunks,
+      |chunk: &mut [Circle]| chunk.iter_mut().for_each(&f),
+    );
+  }
+
+  // Eases circles towards the size the field asks for
+  // where they are
+  #[inline]
+  fn fit_sizes(&mut self) {
+    let Some(field) = self.size_field.take() else {
+      return;
+    };
+    let (min, max) = self.radius_range;
+    let bounds = self.container.bounds(self.area_size);
+    self.for_each_circle(|c| {
+      let (u, v) = image_coords(bounds, c.position);
+      let target = field.radius(min, max, u, v);
+      let change = (target - c.radius) * Self::SIZE_RATE;
+      if c.asleep && change.abs() > Self::SLEEP_SPEED {
+        c.wake();
+      }
+      c.radius += change;
+    });
+    self.size_field = Some(field);
+  }
+
+  #[inline]
+  fn integrate(&mut self) {
     let delta = self.timescale * (1.0 / self.substeps as f32);
-    let gravity = Vector2::new(0.0, self.gravity) * delta.powi(2);
-    self.circles.iter_mut().for_each(|circle| {
-      let velocity = circle.position - circle.last_position;
+    let time = self.time();
+    let gravity = self.gravity_track.at(time, self.gravity) * delta.powi(2);
+    let fields = std::mem::take(&mut self.fields);
+    // Fields scale with the default gravity rather than the
+    // set one, so they still work without gravity
+    let (area, base) = (self.area_size, self.area_size.1);
+    self.for_each_circle(|circle| {
+      if circle.asleep {
+        return;
+      }
+      let mut velocity = circle.position - circle.last_position;
+      if velocity.length2() < Self::SLEEP_SPEED.powi(2) {
+        circle.quiet = circle.quiet.saturating_add(1);
+      } else {
+        circle.quiet = 0;
+      }
+      let mut acceleration = gravity;
+      for field in fields.iter().filter(|f| f.active(time)) {
+        let (force, drag) = field.force(area, base, circle.position);
+        acceleration += force * delta.powi(2);
+        velocity *= (1.0 - drag * delta).max(0.0);
+      }
       circle.last_position = circle.position;
-      circle.position = circle.position + velocity + gravity;
+      circle.position = circle.position + velocity + acceleration;
     });
+    self.fields = fields;
   }
 
   #[inline]
-  async fn collide(&mut self) {
-    for i in 0..self.circles.len() {
-      // Apply gravity
-      for j in i..self.circles.len() {
-        let circle = &self.circles[i].position;
-        let other = &self.circles[j].position;
-        let this_radius = self.circles[i].radius;
-        let other_radius = self.circles[i].radius;
-        let diameter = this_radius + other_radius;
-        if circle.x < other.x - diameter {
-          break; // No further collisions possible
-        }
-        let dy = (circle.y - other.y).abs();
-        if dy >= diameter {
-          continue; // Skip over obvious noncollisions
-        }
-        let combined = *circle - *other;
-        let distance_squared = combined.length2();
-        if distance_squared >= diameter.powi(2) || distance_squared == 0.0 {
-          continue;
-        }
-        // Finally, resort to expensive calculation
-        let distance = distance_squared.sqrt();
-        let normalized = combined * (1.0 / distance);
-        let delta = 0.5 * self.response_mod * (distance - diameter);
-        self.circles[i].position -= normalized * delta * 0.5;
-        self.circles[j].position += normalized * delta * 0.5;
+  fn resolve(circles: &mut [Circle], i: usize, j: usize, response_mod: f32) {
+    if circles[i].asleep && circles[j].asleep {
+      return;
+    }
+    let circle = circles[i].position;
+    let other = circles[j].position;
+    let diameter = circles[i].radius + circles[j].radius;
+    let dy = (circle.y - other.y).abs();
+    if dy >= diameter {
+      return; // Skip over obvious noncollisions
+    }
+    let combined = circle - other;
+    let distance_squared = combined.length2();
+    if distance_squared >= diameter.powi(2) || distance_squared == 0.0 {
+      return;
+    }
+    // Finally, resort to expensive calculation
+    let distance = distance_squared.sqrt();
+    let normalized = combined * (1.0 / distance);
+    let delta = 0.5 * response_mod * (distance - diameter);
+    // Anything pushing into a sleeping circle wakes it
+    for k in [i, j] {
+      if circles[k].asleep {
+        circles[k].wake();
       }
     }
+    circles[i].position -= normalized * delta * 0.5;
+    circles[j].position += normalized * delta * 0.5;
   }
 
+  // Columns only reach into the column to their right, so
+  // all even columns can be solved at once, then all odd
+  // ones. The order within a column never changes, which
+  // keeps the result independent of the thread count
   #[inline]
-  fn constrain_rect(&mut self) {
-    self
-      .circles
-      .iter_mut()
-      .map(|c| &mut c.position)
-      .for_each(|pos| {
-        pos.x = pos
-          .x
-          .clamp(self.circle_radius, self.area_size.0 - self.circle_radius);
-        pos.y = pos
-          .y
-          .clamp(self.circle_radius, self.area_size.1 - self.circle_radius);
+  fn collide(&mut self) {
+    let response_mod = self.response_mod;
+    let workers = self.workers(self.circles.len());
+    for phase in 0..2 {
+      let mut rest = &mut self.circles[..];
+      let mut consumed = 0;
+      let mut tasks = vec![];
+      for col in (phase..self.grid.columns()).step_by(2) {
+        let range = self.grid.column_range(col..col + 2);
+        let (_, tail) = rest.split_at_mut(range.start - consumed);
+        let (circles, tail) = tail.split_at_mut(range.len());
+        rest = tail;
+        consumed = range.end;
+        tasks.push((col, circles));
+      }
+      let grid = &self.grid;
+      // Cells with only sleeping circles have nothing to
+      // solve, so a resting pile costs little more than
+      // building the grid
+      par_each(self.pool.as_deref(), workers, tasks, |(col, circles)| {
+        grid.active_pairs(
+          col,
+          circles,
+          |c| !c.asleep,
+          |circles, i, j| Self::resolve(circles, i, j, response_mod),
+        );
       });
+    }
   }
 
   #[inline]
-  pub async fn step(&mut self) {
-    if self.circles.len() < self.max_circles {
-      self.launch();
+  fn constrain(&mut self) {
+    let container = self.container.clone();
+    let area = self.area_size;
+    let floor_open = self.floor_open;
+    self.for_each_circle(|c| {
+      if c.asleep {
+        return;
+      }
+      let position = container.constrain(area, c.position, c.radius);
+      let push = position - c.position;
+      // An open floor lets circles through walls facing up
+      if floor_open && push.y < 0.0 && -push.y > push.x.abs() {
+        return;
+      }
+      c.position = position;
+    });
+  }
+
+  fn set_field_enabled(&mut self, name: &str, enabled: bool) {
+    for field in &mut self.fields {
+      if field.name.as_deref() == Some(name) {
+        field.enabled = enabled;
+      }
     }
-    if self.circles.len() < self.max_circles {
-      self.launch2();
+  }
+
+  // Removes circles that fell out of the area
+  fn drain(&mut self) {
+    let bottom = self.area_size.1;
+    let (drained, fallen) = (&mut self.drained, &mut self.fallen);
+    self.circles.retain(|c| {
+      let inside = c.position.y - c.radius < bottom;
+      if !inside {
+        drained.push((c.index, c.position, c.radius));
+        fallen.push((c.index, c.radius));
+      }
+      inside
+    });
+  }
+
+  /// Number of circles resting and skipped by the solver
+  pub fn sleeping(&self) -> usize {
+    self.circles.iter().filter(|c| c.asleep).count()
+  }
+
+  fn wake_all(&mut self) {
+    self.circles.iter_mut().for_each(Circle::wake);
+  }
+
+  // Whether gravity, the fields or the obstacles are
+  // different from the last step. Only depends on the clock,
+  // so sleeping works out the same however the simulation
+  // was started
+  fn forces_changed(&self) -> bool {
+    if self.clock == 0 {
+      return false;
     }
+    let step = self.timescale;
+    let (now, before) = (self.time(), self.time() - step);
+    let gravity = |t| {
+      let g = self.gravity_track.at(t, 1.0);
+      (g.x.to_bits(), g.y.to_bits())
+    };
+    gravity(now) != gravity(before)
+      || self
+        .fields
+        .iter()
+        .any(|f| f.active(now) != f.active(before))
+      || (!self.obstacles.is_empty()
+        && self
+          .remove_obstacles_at
+          .is_some_and(|at| before < at && at <= now))
+  }
+
+  // Puts groups of touching circles to sleep once every one
+  // of them has been resting for long enough, and wakes the
+  // whole group when any of them moves. Sleeping one circle
+  // at a time would leave it hanging when those under it
+  // move away
+  fn sleep_islands(&mut self) {
+    let awake = self.circles.iter().any(|c| !c.asleep);
+    let resting = self.circles.iter().any(|c| c.quiet >= Self::SLEEP_DELAY);
+    if !awake || !resting {
+      return;
+    }
+    self.sort();
+    let mut parent: Vec<usize> = (0..self.circles.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+      while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+      }
+      i
+    }
+    for col in 0..self.grid.columns() {
+      let offset = self.grid.column_range(col..col + 2).start;
+      let circles = &self.circles;
+      self.grid.column_pairs(col, |i, j| {
+        let (a, b) = (&circles[i + offset], &circles[j + offset]);
+        let reach = a.radius + b.radius + Self::CONTACT_GAP;
+        if (a.position - b.position).length2() < reach.powi(2) {
+          let (a, b) =
+            (root(&mut parent, i + offset), root(&mut parent, j + offset));
+          // Lowest index as the root keeps this independent
+          // of the pair order
+          parent[a.max(b)] = a.min(b);
+        }
+      });
+    }
+    let mut quietest = vec![u16::MAX; self.circles.len()];
+    for i in 0..self.circles.len() {
+      let r = root(&mut parent, i);
+      quietest[r] = quietest[r].min(self.circles[i].quiet);
+    }
+    for i in 0..self.circles.len() {
+      let r = root(&mut parent, i);
+      let c = &mut self.circles[i];
+      let asleep = quietest[r] >= Self::SLEEP_DELAY;
+      if asleep && !c.asleep {
+        c.last_position = c.position;
+      }
+      c.asleep = asleep;
+    }
+  }
+
+  // Pushes circles out of obstacles, bouncing them off
+  #[inline]
+  fn collide_obstacles(&mut self) {
+    if !self.obstacles_active() {
+      return;
+    }
+    let obstacles = std::mem::take(&mut self.obstacles);
+    let area = self.area_size;
+    self.for_each_circle(|c| {
+      if c.asleep {
+        return;
+      }
+      for obstacle in &obstacles {
+        let Some((position, normal)) =
+          obstacle.push_out(area, c.position, c.radius)
+        else {
+          continue;
+        };
+        c.position = position;
+        // Reflect the velocity along the normal
+        let velocity = c.position - c.last_position;
+        let along = velocity.x * normal.x + velocity.y * normal.y;
+        if along < 0.0 {
+          let velocity = velocity - normal * (along * (1.0 + Self::BOUNCE));
+          c.last_position = c.position - velocity;
+        }
+      }
+    });
+    self.obstacles = obstacles;
+  }
+
+  #[inline]
+  pub async fn step(&mut self) {
+    if self.forces_changed() {
+      self.wake_all();
+    }
+    self.run_timeline();
+    self.emit();
+    self.fit_sizes();
 
     for _ in 0..self.substeps {
-      self.constrain_rect();
-      self.sort().await;
-      self.collide().await;
-      self.integrate().await;
+      // Nothing can move until something wakes up
+      if self.circles.iter().any(|c| !c.asleep) {
+        self.constrain();
+        self.sort();
+        self.collide();
+        self.collide_obstacles();
+        self.integrate();
+      }
       self.clock += 1;
     }
+    self.sleep_islands();
+    let rest = self.timescale;
+    for c in &mut self.circles {
+      if (c.position - c.last_position).length2() < Self::REST_SPEED.powi(2) {
+        c.rested += rest;
+      }
+    }
+    if self.floor_open {
+      self.drain();
+    }
+  }
+
+  // Steps once, first adding the current state to
+  // `recording` when a frame is due. Recording stops if the
+  // frames can't be stored
+  async fn record_step(&mut self, recording: &mut Option<Recording>) {
+    if let Some(rec) = recording {
+      if self.tick().is_multiple_of(rec.every()) {
+        let circles = self.circles.iter().map(|c| Sample {
+          index: c.index,
+          position: c.position,
+          radius: c.radius,
+          rested: c.rested,
+        });
+        let (obstacles, time) = (self.obstacles_active(), self.time());
+        if let Err(e) = rec.push(circles, obstacles, time) {
+          eprintln!("Could not record frames, they will be simulated again:");
+          eprintln!("{}", e);
+          *recording = None;
+        }
+      }
+    }
+    self.step().await;
+    let checksum = self.trace.is_some().then(|| self.checksum());
+    let tick = self.tick();
+    if let (Some(trace), Some(checksum)) = (&mut self.trace, checksum) {
+      trace.checksums.push(checksum);
+      if trace.capture == Some(tick) {
+        trace.circles = self.circles.clone();
+      }
+    }
   }
 
   #[inline]
@@ -246,3 +1673,393 @@ impl Simulation {
     self.circles.len()
   }
 }
+
+// How far a pass has got settling
+#[derive(Copy, Clone, Serialize, Deserialize)]
+enum Settling {
+  // Spawning, with the number of circles and the time at
+  // the last spawn
+  Filling(usize, f32),
+  // Waiting for the forces to stop changing and everything
+  // that fell out to be back
+  Forces,
+  // Steps left to run after that
+  Post(usize),
+  Done,
+}
+
+impl Settling {
+  fn start(sim: &Simulation) -> Self {
+    Self::Filling(sim.spawned, sim.time())
+  }
+}
+
+// The bowl filling up and settling, once per size pass and
+// then once per picture
+#[derive(Serialize, Deserialize)]
+struct Pass {
+  sim: Simulation,
+  recording: Option<Recording>,
+  settling: Settling,
+  #[serde(skip)]
+  progress: Option<ProgressBar>,
+}
+
+impl Pass {
+  fn new(
+    config: &SimulationConfig,
+    seed: usize,
+    radii: Vec<f32>,
+    trace: Option<Trace>,
+  ) -> Self {
+    let mut sim = Simulation::setup(config, seed, radii);
+    sim.trace = trace;
+    let recording = config
+      .record
+      .map(|every| Recording::new(every, sim.area_size));
+    Self {
+      settling: Settling::start(&sim),
+      sim,
+      recording,
+      progress: None,
+    }
+  }
+
+  // Steps until the bowl is full and the forces stop
+  // changing, then for `post_process` more steps, recording
+  // frames if asked to. Stops early once `steps` runs out,
+  // returning whether it settled
+  async fn run_until_settled(
+    &mut self,
+    post_process: usize,
+    steps: &mut usize,
+  ) -> bool {
+    if let Settling::Done = self.settling {
+      return true;
+    }
+    let sim = &mut self.sim;
+    let progress = self.progress.get_or_insert_with(|| {
+      let progress =
+        make_progress("Preprocessing", (sim.max_circles + post_process) as u64);
+      progress.set_position(match self.settling {
+        Settling::Filling(..) => sim.spawned,
+        Settling::Post(left) => sim.circles.len() + post_process - left,
+        _ => sim.circles.len(),
+      } as u64);
+      progress
+    });
+    loop {
+      match self.settling {
+        Settling::Filling(..)
+          if sim.spawned >= sim.max_circles || sim.emitters_finished() =>
+        {
+          self.settling = Settling::Forces;
+          progress.set_position(sim.circles.len() as u64);
+        },
+        // Settling only counts once the forces stop changing
+        // and everything that fell out is back
+        Settling::Forces
+          if sim.time() >= sim.last_change() && !sim.refilling() =>
+        {
+          self.settling = Settling::Post(post_process);
+        },
+        Settling::Post(0) | Settling::Done => {
+          progress.finish();
+          self.settling = Settling::Done;
+          return true;
+        },
+        _ if *steps == 0 => return false,
+        Settling::Filling(spawned, at) => {
+          *steps -= 1;
+          sim.record_step(&mut self.recording).await;
+          progress.set_position(sim.spawned as u64);
+          if sim.spawned != spawned {
+            self.settling = Settling::start(sim);
+          } else if sim.time() - at > Simulation::STALL_SECONDS
+            && sim.time() > sim.last_change()
+          {
+            eprintln!(
+              "The emitters stopped after {} of {} circles, so the bowl \
+               won't be full",
+              sim.spawned, sim.max_circles
+            );
+            self.settling = Settling::Forces;
+            progress.set_position(sim.circles.len() as u64);
+          }
+        },
+        Settling::Forces => {
+          *steps -= 1;
+          sim.record_step(&mut self.recording).await;
+          progress.set_position(sim.circles.len() as u64);
+        },
+        Settling::Post(left) => {
+          *steps -= 1;
+          sim.record_step(&mut self.recording).await;
+          progress.inc(1);
+          self.settling = Settling::Post(left - 1);
+        },
+      }
+    }
+  }
+
+  // Radius of where each circle landed on `field`, and how
+  // far that is from the radius it spawned with on
+  // average, as a fraction of the radius range
+  fn landed(
+    &self,
+    config: &SimulationConfig,
+    field: &SizeField,
+  ) -> (Vec<f32>, f32) {
+    let sim = &self.sim;
+    let (min, max) = config.radius_range;
+    let bounds = sim.container.bounds(sim.area_size);
+    let mut landed = vec![0.0; sim.spawned];
+    // Circles that fell out never landed, so they keep
+    // their size and don't count towards the change
+    for &(index, _, radius) in &sim.drained {
+      landed[index] = radius;
+    }
+    let mut change = 0.0;
+    for c in &sim.circles {
+      let (u, v) = image_coords(bounds, c.position);
+      landed[c.index] = field.radius(min, max, u, v);
+      let spawned = sim.radii.get(c.index).unwrap_or(&config.circle_radius);
+      change += (landed[c.index] - spawned).abs();
+    }
+    change /= sim.circles().max(1) as f32 * (max - min).max(f32::EPSILON);
+    (landed, change)
+  }
+}
+
+// Colors and circles of each picture formed so far, and
+// when the change to it happens
+#[derive(Default, Serialize, Deserialize)]
+struct Formed {
+  max_circles: usize,
+  colors: Vec<Vec<Color>>,
+  hidden: Vec<Vec<bool>>,
+  sites: Vec<Vec<Site>>,
+  changes: Vec<(f32, f32)>,
+}
+
+/// Preprocessing partway through. Saved every so often with
+/// [`state::save_checkpoint`] when
+/// [`SimulationConfig::checkpoint`] is set, so a run cut
+/// short can carry on from the last save
+#[derive(Serialize, Deserialize)]
+pub struct Preprocessing {
+  // Hash of the images and options it was started with
+  fingerprint: u64,
+  seed: usize,
+  // Number of images, each formed in turn
+  pictures: usize,
+  // Size passes run so far, and the best of them
+  passes: SizePasses<Pass>,
+  // The size pass being run, or the pass that was kept
+  // once the sizes fit
+  current: Pass,
+  // Set once the sizes fit
+  formed: Option<Formed>,
+  // Kept for every pass when verifying
+  trace: Option<Trace>,
+}
+
+impl Preprocessing {
+  // Steps run between saves
+  const STEPS: usize = 1000;
+
+  /// Preprocessing of `images` with `config`, not started
+  /// yet
+  pub fn new(
+    config: &SimulationConfig,
+    images: &[ImageBuffer<Rgb<u8>, Vec<u8>>],
+  ) -> Self {
+    Self::traced(config, images, None)
+  }
+
+  fn traced(
+    config: &SimulationConfig,
+    images: &[ImageBuffer<Rgb<u8>, Vec<u8>>],
+    trace: Option<Trace>,
+  ) -> Self {
+    let seed = config
+      .seed
+      .unwrap_or_else(|| Simulation::image_seed(&images[0]));
+    Self {
+      fingerprint: Self::fingerprint(config, images),
+      seed,
+      pictures: images.len(),
+      passes: SizePasses::new(),
+      current: Pass::new(config, seed, vec![], trace.clone()),
+      formed: None,
+      trace,
+    }
+  }
+
+  // Stable hash of everything that changes the result
+  fn fingerprint(
+    config: &SimulationConfig,
+    images: &[ImageBuffer<Rgb<u8>, Vec<u8>>],
+  ) -> u64 {
+    let mut config = config.clone();
+    config.threads = 1;
+    config.checkpoint = None;
+    // The debug output leaves out the field's values
+    let field = serde_json::to_string(&config.size_field).unwrap_or_default();
+    let hash = fnv1a(FNV_OFFSET, format!("{:?}", config).as_bytes());
+    let hash = fnv1a(hash, field.as_bytes());
+    images.iter().fold(hash, |hash, img| {
+      let hash = fnv1a(hash, &img.width().to_le_bytes());
+      let hash = fnv1a(hash, &img.height().to_le_bytes());
+      fnv1a(hash, img.as_raw())
+    })
+  }
+
+  /// Recordings of the passes that are kept
+  pub fn recordings(&mut self) -> impl Iterator<Item = &mut Recording> {
+    let best = self.passes.best_mut().and_then(|p| p.recording.as_mut());
+    best.into_iter().chain(self.current.recording.as_mut())
+  }
+
+  /// Runs up to `steps` more steps, returning whether
+  /// every picture has formed
+  pub async fn run(
+    &mut self,
+    config: &SimulationConfig,
+    images: &[ImageBuffer<Rgb<u8>, Vec<u8>>],
+    mut steps: usize,
+  ) -> bool {
+    loop {
+      if self
+        .formed
+        .as_ref()
+        .is_some_and(|f| f.colors.len() == self.pictures)
+      {
+        return true;
+      }
+      let settled = self
+        .current
+        .run_until_settled(config.post_process, &mut steps)
+        .await;
+      if !settled {
+        return false;
+      }
+      if self.formed.is_none() {
+        // Where a circle lands is only known after
+        // simulating, so with a size field each pass spawns
+        // the circles at the size of where they landed in
+        // the last one. They still grow or shrink to fit
+        // wherever they end up, so passes stop once that
+        // stops getting smaller, keeping the pass that
+        // needed the least of it
+        if let Some(field) = &config.size_field {
+          let (landed, change) = self.current.landed(config, field);
+          let next = Pass::new(config, self.seed, landed, self.trace.clone());
+          let pass = std::mem::replace(&mut self.current, next);
+          if self.passes.offer(pass, change) {
+            continue;
+          }
+          self.current = std::mem::take(&mut self.passes).best().unwrap();
+        }
+        self.formed = Some(Formed {
+          max_circles: self.current.sim.spawned,
+          ..Formed::default()
+        });
+      }
+      let formed = self.formed.as_mut().unwrap();
+      let sim = &mut self.current.sim;
+      // Circles left out keep the color they had
+      let img = &images[formed.colors.len()];
+      sim.assign_colors_from_image(img, config).await;
+      formed.colors.push(sim.colors.clone());
+      formed.hidden.push(sim.hidden.clone());
+      formed.sites.push(sim.sites());
+      if formed.colors.len() < self.pictures {
+        // Circles that fell out are sent back in, so only
+        // the ones falling out from here on can be left out
+        sim.drained.clear();
+        let from = sim.time() + config.show;
+        sim.timeline.extend(config.change.cues(from));
+        formed.changes.push((from, from + config.change.seconds()));
+        self.current.settling = Settling::start(sim);
+        self.current.progress = None;
+      }
+    }
+  }
+
+  /// Once every picture has formed, the same as
+  /// [`Simulation::simulate_images`] returns
+  pub fn finish(
+    self,
+    config: &SimulationConfig,
+  ) -> (Simulation, usize, usize, Option<Recording>) {
+    let Pass {
+      mut sim, recording, ..
+    } = self.current;
+    let Formed {
+      max_circles,
+      mut colors,
+      hidden,
+      sites,
+      changes,
+    } = self.formed.expect("every picture formed");
+    let total_iterations = sim.clock;
+    let expected = sim.checksum();
+    if let Some(source) = &config.palette {
+      // One palette shared by every picture
+      let all: Vec<Color> = colors
+        .iter()
+        .zip(&hidden)
+        .flat_map(|(c, h)| {
+          let shown = c[..max_circles].iter().zip(h).filter(|(_, h)| !**h);
+          shown.map(|(c, _)| *c).collect::<Vec<_>>()
+        })
+        .collect();
+      let palette = source.build(&all);
+      for (colors, sites) in colors.iter_mut().zip(&sites) {
+        if config.dither > 0.0 {
+          let area = sim.area_size;
+          dither(&palette, colors, sites, area, config.dither);
+        } else {
+          palette.snap(&mut colors[..max_circles]);
+        }
+      }
+      sim.palette = palette.colors;
+    }
+    let mut colors = colors.into_iter();
+    let mut hidden = hidden.into_iter();
+    sim.colors = colors.next().unwrap();
+    sim.hidden = hidden.next().unwrap();
+    sim.pictures = changes
+      .into_iter()
+      .zip(colors.zip(hidden))
+      .map(|((from, until), (colors, hidden))| Picture {
+        from,
+        until,
+        colors,
+        hidden,
+      })
+      .collect();
+    if recording.is_some() {
+      // Colors are only known now, after the circles spawned
+      let time = sim.time();
+      for i in 0..sim.circles.len() {
+        sim.circles[i].color = sim.color_at(sim.circles[i].index, time);
+      }
+      sim.expected = Some(expected);
+      return (sim, total_iterations, max_circles, recording);
+    }
+    // Start over from scratch so the replay matches, keeping
+    // the colors and sizes that were found, and the changes
+    // between pictures
+    let mut fresh = Simulation::setup(config, self.seed, sim.radii.clone());
+    fresh.colors = std::mem::take(&mut sim.colors);
+    fresh.hidden = std::mem::take(&mut sim.hidden);
+    fresh.pictures = std::mem::take(&mut sim.pictures);
+    fresh.timeline = std::mem::take(&mut sim.timeline);
+    fresh.expected = Some(expected);
+    fresh.trace = sim.trace.take();
+    fresh.palette = std::mem::take(&mut sim.palette);
+    (fresh, total_iterations, max_circles, None)
+  }
+}
@@ -0,0 +1,203 @@
+use image::{imageops::FilterType, ImageBuffer, Luma, Rgb};
+use serde::{Deserialize, Serialize};
+
+/// Grayscale field stretched over the container, like the
+/// input image, that decides how big circles are. Values go
+/// from 0 for the smallest circles to 1 for the largest
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SizeField {
+  width: u32,
+  height: u32,
+  values: Vec<f32>,
+}
+
+impl SizeField {
+  // Longest side the detail of an image is measured at
+  const DETAIL_SIZE: u32 = 128;
+
+  pub fn new(width: u32, height: u32, values: Vec<f32>) -> Self {
+    assert_eq!(values.len(), (width * height) as usize);
+    Self {
+      width,
+      height,
+      values,
+    }
+  }
+
+  /// Uses the brightness of a grayscale image, so dark
+  /// areas get small circles and light areas big ones
+  pub fn from_image(img: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Self {
+    let values = img.pixels().map(|p| p[0] as f32 / 255.0).collect();
+    Self::new(img.width(), img.height(), values)
+  }
+
+  /// Measures how much detail each part of `img` has, from
+  /// the strength of its edges and how much the brightness
+  /// varies nearby. Busy areas get small circles and flat
+  /// areas big ones
+  pub fn from_detail(img: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Self {
+    // Only the rough layout matters, so save some work
+    let scale = (Self::DETAIL_SIZE as f32
+      / img.width().max(img.height()) as f32)
+      .min(1.0);
+    let width = ((img.width() as f32 * scale).round() as u32).max(1);
+    let height = ((img.height() as f32 * scale).round() as u32).max(1);
+    let small =
+      image::imageops::resize(img, width, height, FilterType::Triangle);
+    let luma: Vec<f32> = small
+      .pixels()
+      .map(|p| {
+        (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32)
+          / 255.0
+      })
+      .collect();
+    let (w, h) = (width as i64, height as i64);
+    let at = |x: i64, y: i64| {
+      luma[(y.clamp(0, h - 1) * w + x.clamp(0, w - 1)) as usize]
+    };
+    // Sobel edge strength
+    let mut edges = Vec::with_capacity(luma.len());
+    for y in 0..h {
+      for x in 0..w {
+        let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+          - at(x - 1, y - 1)
+          - 2.0 * at(x - 1, y)
+          - at(x - 1, y + 1);
+        let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+          - at(x - 1, y - 1)
+          - 2.0 * at(x, y - 1)
+          - at(x + 1, y - 1);
+        edges.push((gx * gx + gy * gy).sqrt());
+      }
+    }
+    // Spread both measures over a neighbourhood, so zones
+    // are big enough for a few circles
+    let reach = (width.max(height) / 32).max(1) as usize;
+    let edges = box_blur(&edges, width, height, reach);
+    let mean = box_blur(&luma, width, height, reach);
+    let squares: Vec<f32> = luma.iter().map(|l| l * l).collect();
+    let mean_squares = box_blur(&squares, width, height, reach);
+    let detail: Vec<f32> = edges
+      .iter()
+      .zip(mean.iter().zip(mean_squares.iter()))
+      .map(|(edge, (mean, squares))| {
+        edge + (squares - mean * mean).max(0.0).sqrt()
+      })
+      .collect();
+    // Scale so only the busiest few percent get the
+    // smallest circles
+    let mut sorted = detail.clone();
+    sorted.sort_by(f32::total_cmp);
+    let high = sorted[(sorted.len() - 1) * 95 / 100].max(f32::EPSILON);
+    let values = detail
+      .iter()
+      .map(|d| 1.0 - (d / high).clamp(0.0, 1.0))
+      .collect();
+    Self::new(width, height, values)
+  }
+
+  /// Value at `u, v`, both fractions of the field
+  #[inline]
+  pub fn sample(&self, u: f32, v: f32) -> f32 {
+    let x = (u.clamp(0.0, 1.0) * (self.width - 1) as f32).round() as u32;
+    let y = (v.clamp(0.0, 1.0) * (self.height - 1) as f32).round() as u32;
+    self.values[(y * self.width + x) as usize]
+  }
+
+  /// Radius between `min` and `max` at `u, v`
+  #[inline]
+  pub fn radius(&self, min: f32, max: f32, u: f32, v: f32) -> f32 {
+    min + (max - min) * self.sample(u, v)
+  }
+}
+
+/// Decides how many times an image is simulated to fit a
+/// size field, keeping the pass whose circles needed the
+/// least growing or shrinking after they landed
+#[derive(Serialize, Deserialize)]
+pub struct SizePasses<T> {
+  passes: usize,
+  best: Option<(T, f32)>,
+}
+
+impl<T> SizePasses<T> {
+  /// Most passes run
+  pub const MAX: usize = 4;
+  /// Average change in size after landing, as a fraction of
+  /// the radius range, below which the sizes count as
+  /// settled. Also the least improvement worth another pass
+  pub const TOLERANCE: f32 = 0.05;
+
+  pub fn new() -> Self {
+    Self {
+      passes: 0,
+      best: None,
+    }
+  }
+
+  /// Takes the result of a pass that left circles `change`
+  /// from the size of where they landed, returning whether
+  /// another pass is worth running
+  pub fn offer(&mut self, result: T, change: f32) -> bool {
+    self.passes += 1;
+    let improved = self
+      .best
+      .as_ref()
+      .is_none_or(|(_, best)| change < best * (1.0 - Self::TOLERANCE));
+    if improved {
+      self.best = Some((result, change));
+    }
+    improved && self.passes < Self::MAX && change >= Self::TOLERANCE
+  }
+
+  pub fn passes(&self) -> usize {
+    self.passes
+  }
+
+  /// The result of the best pass so far
+  pub fn best(self) -> Option<T> {
+    self.best.map(|(result, _)| result)
+  }
+
+  pub fn best_mut(&mut self) -> Option<&mut T> {
+    self.best.as_mut().map(|(result, _)| result)
+  }
+}
+
+impl<T> Default for SizePasses<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+// Averages every value with its neighbours up to `reach`
+// away, one axis at a time
+fn box_blur(values: &[f32], width: u32, height: u32, reach: usize) -> Vec<f32> {
+  let (w, h) = (width as usize, height as usize);
+  let pass = |values: &[f32], step: usize, len: usize, lines: usize| {
+    let line_step = if step == 1 { len } else { 1 };
+    let mut out = vec![0.0; values.len()];
+    for line in 0..lines {
+      let base = line * line_step;
+      for i in 0..len {
+        let range = i.saturating_sub(reach)..(i + reach + 1).min(len);
+        let count = range.len() as f32;
+        out[base + i * step] =
+          range.map(|j| values[base + j * step]).sum::<f32>() / count;
+      }
+    }
+    out
+  };
+  let rows = pass(values, 1, w, h);
+  pass(&rows, w, h, w)
+}
+
+// Printing every value is of no use
+impl std::fmt::Debug for SizeField {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("SizeField")
+      .field("width", &self.width)
+      .field("height", &self.height)
+      .finish_non_exhaustive()
+  }
+}
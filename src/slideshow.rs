@@ -0,0 +1,112 @@
+use std::str::FromStr;
+
+use crate::{
+  helper::parse_numbers,
+  timeline::{Cue, Event},
+};
+
+// Seconds between kicks while shaking
+const KICK_EVERY: f32 = 0.3;
+// Where kicks push away from, as fractions of the area,
+// taken in turn so circles swap sides rather than jumping
+// together
+const KICK_FROM: [(f32, f32); 3] = [(0.3, 1.0), (0.7, 1.0), (0.5, 1.1)];
+
+/// How the bowl goes from one picture to the next. The same
+/// circles are reused, so they get new colors from where
+/// they land afterwards
+#[derive(Clone, Copy, Debug)]
+pub enum Change {
+  /// Kicks the circles up from below every so often for
+  /// `seconds`. `speed` is in pixels per substep
+  Shake { seconds: f32, speed: f32 },
+  /// Opens the floor for `seconds`, then closes it and
+  /// sends the circles that fell out back in through the
+  /// emitters, or along the top once they are done
+  Drain { seconds: f32 },
+}
+
+impl Default for Change {
+  fn default() -> Self {
+    Self::Shake {
+      seconds: 2.0,
+      speed: 6.0,
+    }
+  }
+}
+
+impl Change {
+  pub fn seconds(&self) -> f32 {
+    match self {
+      Self::Shake { seconds, .. } | Self::Drain { seconds } => *seconds,
+    }
+  }
+
+  /// Events making the change, starting at `at` seconds
+  pub fn cues(&self, at: f32) -> Vec<Cue> {
+    match *self {
+      Self::Shake { seconds, speed } => {
+        let kicks = (seconds / KICK_EVERY).ceil().max(1.0) as usize;
+        (0..kicks)
+          .map(|i| {
+            let (x, y) = KICK_FROM[i % KICK_FROM.len()];
+            Cue {
+              at: at + i as f32 * KICK_EVERY,
+              event: Event::Impulse {
+                speed,
+                angle: 0.0,
+                center: Some((x, y)),
+              },
+            }
+          })
+          .collect()
+      },
+      Self::Drain { seconds } => vec![
+        Cue {
+          at,
+          event: Event::OpenFloor,
+        },
+        Cue {
+          at: at + seconds,
+          event: Event::Refill,
+        },
+      ],
+    }
+  }
+}
+
+impl FromStr for Change {
+  type Err = String;
+
+  /// Parses `shake[:seconds[,speed]]` or `drain[:seconds]`
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    let (name, params) = spec.split_once(':').unwrap_or((spec, ""));
+    let values = parse_numbers(params)?;
+    let change = match (name, values.as_slice()) {
+      ("shake", []) => Self::default(),
+      ("shake", [seconds]) => Self::Shake {
+        seconds: *seconds,
+        speed: 6.0,
+      },
+      ("shake", [seconds, speed]) => Self::Shake {
+        seconds: *seconds,
+        speed: *speed,
+      },
+      ("drain", []) => Self::Drain { seconds: 3.0 },
+      ("drain", [seconds]) => Self::Drain { seconds: *seconds },
+      ("shake" | "drain", _) => {
+        return Err(format!("wrong number of values for {}", name))
+      },
+      _ => {
+        return Err(format!(
+          "unknown change '{}', expected shake or drain",
+          name
+        ))
+      },
+    };
+    if change.seconds().is_nan() || change.seconds() <= 0.0 {
+      return Err(format!("the {} change must last more than 0 seconds", name));
+    }
+    Ok(change)
+  }
+}
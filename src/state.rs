@@ -0,0 +1,156 @@
+use std::{
+  fs::File,
+  io::{BufWriter, Error, ErrorKind, Result, Write},
+  path::Path,
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::sim::{Preprocessing, Simulation};
+
+/// Bumped whenever the layout of a saved [`Simulation`]
+/// changes in a way older files can't be read as
+pub const FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize)]
+struct StateRef<'a> {
+  version: u32,
+  iterations: usize,
+  simulation: &'a Simulation,
+}
+
+#[derive(Deserialize)]
+struct State {
+  iterations: usize,
+  simulation: Simulation,
+}
+
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+  version: u32,
+  preprocessing: &'a Preprocessing,
+}
+
+#[derive(Deserialize)]
+struct Checkpoint {
+  preprocessing: Preprocessing,
+}
+
+// Only the version, so mismatches can be reported before
+// trying to read the rest of the file
+#[derive(Deserialize)]
+struct Header {
+  version: u32,
+}
+
+fn check_version(text: &str) -> Result<()> {
+  let header: Header = serde_json::from_str(text)?;
+  if header.version != FORMAT_VERSION {
+    return Err(Error::new(
+      ErrorKind::InvalidData,
+      format!(
+        "state file has version {}, expected {}",
+        header.version, FORMAT_VERSION
+      ),
+    ));
+  }
+  Ok(())
+}
+
+/// Writes the full state of `sim` to `path`, together with
+/// the clock value the animation should run until.
+/// Stepping a loaded copy gives exactly the same results as
+/// stepping `sim` would. Only meant for once preprocessing
+/// is done, [`save_checkpoint`] saves it partway through
+pub fn save<P: AsRef<Path>>(
+  path: P,
+  sim: &Simulation,
+  iterations: usize,
+) -> Result<()> {
+  let writer = BufWriter::new(File::create(path)?);
+  let state = StateRef {
+    version: FORMAT_VERSION,
+    iterations,
+    simulation: sim,
+  };
+  serde_json::to_writer(writer, &state).map_err(Error::from)
+}
+
+/// Reads a state written by [`save`], returning the
+/// simulation and the clock value to run until
+pub fn load<P: AsRef<Path>>(path: P) -> Result<(Simulation, usize)> {
+  let text = std::fs::read_to_string(path)?;
+  check_version(&text)?;
+  let state: State = serde_json::from_str(&text)?;
+  Ok((state.simulation, state.iterations))
+}
+
+/// Writes how far `pre` has got to `path`, replacing the
+/// file only once the new one is complete. Recorded frames
+/// go in files next to it, named after it, and any of
+/// those no longer needed are removed
+pub fn save_checkpoint<P: AsRef<Path>>(
+  path: P,
+  pre: &mut Preprocessing,
+) -> Result<()> {
+  static COUNT: AtomicUsize = AtomicUsize::new(0);
+  let path = path.as_ref();
+  let name = path
+    .file_name()
+    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not a file name"))?
+    .to_string_lossy()
+    .into_owned();
+  let mut kept = vec![];
+  for recording in pre.recordings() {
+    let file = recording.kept().map(Path::to_owned).unwrap_or_else(|| {
+      let count = COUNT.fetch_add(1, Ordering::Relaxed);
+      path.with_file_name(format!(
+        "{}.{}-{}.rec",
+        name,
+        std::process::id(),
+        count
+      ))
+    });
+    recording.keep(&file)?;
+    kept.extend(file.file_name().map(ToOwned::to_owned));
+  }
+  let temp = path.with_file_name(format!("{}.tmp", name));
+  let mut writer = BufWriter::new(File::create(&temp)?);
+  let checkpoint = CheckpointRef {
+    version: FORMAT_VERSION,
+    preprocessing: pre,
+  };
+  serde_json::to_writer(&mut writer, &checkpoint)?;
+  writer.flush()?;
+  writer.get_ref().sync_all()?;
+  std::fs::rename(&temp, path)?;
+  // Frames of passes that were dropped since the last save
+  let dir = match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  };
+  for entry in std::fs::read_dir(dir)?.flatten() {
+    let file = entry.file_name();
+    let ours = file.to_str().is_some_and(|f| {
+      f.starts_with(&format!("{}.", name)) && f.ends_with(".rec")
+    });
+    if ours && !kept.contains(&file) {
+      let _ = std::fs::remove_file(entry.path());
+    }
+  }
+  Ok(())
+}
+
+/// Reads a checkpoint written by [`save_checkpoint`], to
+/// carry on preprocessing from
+pub fn load_checkpoint<P: AsRef<Path>>(path: P) -> Result<Preprocessing> {
+  let text = std::fs::read_to_string(path)?;
+  check_version(&text)?;
+  let checkpoint: Checkpoint = serde_json::from_str(&text)?;
+  let mut pre = checkpoint.preprocessing;
+  for recording in pre.recordings() {
+    recording.reopen()?;
+  }
+  Ok(pre)
+}
@@ -1,19 +1,1082 @@
 #[cfg(test)]
 #[test]
 fn bench_async() {
-  use std::io::Write;
   flexi_logger::Logger::try_with_str("debug").unwrap();
 
-  let image = image::io::Reader::open(
-    "resources/mona
-  lisa.png",
-  )
-  .unwrap()
-  .decode()
-  .unwrap()
-  .to_rgb8();
+  let _image = image::io::Reader::open("resources/mona lisa.png")
+    .unwrap()
+    .decode()
+    .unwrap()
+    .to_rgb8();
   // let gif = pollster::block_on(crate::generate_async(image, None));
   // let mut file =
   // std::fs::File::create("test.gif").unwrap();
   // file.write(&gif).unwrap();
 }
+
+// Settings most simulation tests start from, a small bowl
+// that fills quickly
+#[cfg(test)]
+fn small_config() -> crate::sim::SimulationConfigBuilder {
+  crate::sim::SimulationConfig::builder()
+    .size(96.0, 96.0)
+    .circle_radius(3.0)
+    .seed(11)
+}
+
+#[cfg(test)]
+fn solid_image(rgb: [u8; 3]) -> image::RgbImage {
+  image::RgbImage::from_pixel(8, 8, image::Rgb(rgb))
+}
+
+#[cfg(test)]
+fn gray_image() -> image::RgbImage {
+  solid_image([128; 3])
+}
+
+// Preprocesses `images`, returning the simulation ready to
+// replay, the clock it ends on and the number of circles
+#[cfg(test)]
+fn simulate(
+  config: &crate::sim::SimulationConfig,
+  images: Vec<image::RgbImage>,
+) -> (crate::sim::Simulation, usize, usize) {
+  let (sim, it, max_circles, _) =
+    pollster::block_on(crate::sim::Simulation::simulate_images(config, images));
+  (sim, it, max_circles)
+}
+
+// Like [`simulate`], with the replay run to the end
+#[cfg(test)]
+fn replay(
+  config: &crate::sim::SimulationConfig,
+  images: Vec<image::RgbImage>,
+) -> (crate::sim::Simulation, usize) {
+  let (mut sim, it, max_circles) = simulate(config, images);
+  pollster::block_on(sim.steps((it - sim.clock) / sim.substeps));
+  (sim, max_circles)
+}
+
+#[cfg(test)]
+#[test]
+fn grid_pairs_match_brute_force() {
+  use crate::{grid::Grid, helper::Vector2};
+  let size = (97.0, 61.0);
+  let mut points: Vec<Vector2> = (0..400)
+    .map(|i| {
+      let t = i as f32;
+      Vector2::new(
+        (t * 12.9898).sin().abs() * size.0,
+        (t * 78.233).cos().abs() * size.1,
+      )
+    })
+    .collect();
+  let reach = 6.0;
+  let mut grid = Grid::new();
+  grid.build(&mut points, &mut vec![], reach, size, |p| *p);
+  let mut found = vec![];
+  for col in 0..grid.columns() {
+    let offset = grid.column_range(col..col + 2).start;
+    grid.column_pairs(col, |a, b| {
+      let (a, b) = (points[a + offset], points[b + offset]);
+      if (a - b).length2() < reach * reach {
+        found.push(((a.x, a.y), (b.x, b.y)));
+      }
+    });
+  }
+  let mut expected = 0;
+  for i in 0..points.len() {
+    for j in i + 1..points.len() {
+      if (points[i] - points[j]).length2() < reach * reach {
+        expected += 1;
+      }
+    }
+  }
+  assert_eq!(found.len(), expected);
+}
+
+#[cfg(test)]
+#[test]
+fn threads_do_not_change_result() {
+  use crate::sim::Simulation;
+  let run = |threads| {
+    let mut sim = Simulation::new(128.0, 128.0, 2.0, 7);
+    sim.threads = threads;
+    pollster::block_on(sim.steps(700));
+    // Circles have to end up in the same places by index,
+    // or the colors land on the wrong ones
+    let mut circles = sim
+      .circles
+      .iter()
+      .map(|c| {
+        let (x, y) = (c.position.x.to_bits(), c.position.y.to_bits());
+        (c.index(), x, y, c.radius.to_bits())
+      })
+      .collect::<Vec<_>>();
+    circles.sort();
+    circles
+  };
+  let single = run(1);
+  assert!(single.len() > 1000);
+  assert_eq!(single, run(4));
+  assert_eq!(single, run(3));
+}
+
+#[cfg(test)]
+#[test]
+fn saved_state_continues_identically() {
+  use crate::sim::Simulation;
+  let path = std::env::temp_dir().join("fishbowl_state_test.json");
+  let mut sim = Simulation::new(96.0, 96.0, 3.0, 11);
+  pollster::block_on(sim.steps(150));
+  crate::state::save(&path, &sim, 1234).unwrap();
+  let (mut loaded, iterations) = crate::state::load(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+  assert_eq!(iterations, 1234);
+  pollster::block_on(sim.steps(150));
+  pollster::block_on(loaded.steps(150));
+  assert_eq!(sim.clock, loaded.clock);
+  let positions = |sim: &Simulation| {
+    sim
+      .circles
+      .iter()
+      .map(|c| (c.position.x.to_bits(), c.position.y.to_bits()))
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(positions(&sim), positions(&loaded));
+}
+
+#[cfg(test)]
+#[test]
+fn settled_circles_sleep_until_disturbed() {
+  use crate::{sim::Simulation, timeline::Event};
+  let mut sim = Simulation::new(96.0, 96.0, 3.0, 11);
+  pollster::block_on(sim.steps(1500));
+  assert!(sim.sleeping() > sim.circles() / 2);
+  sim.apply(Event::Impulse {
+    speed: 1.0,
+    angle: -90.0,
+    center: None,
+  });
+  assert_eq!(sim.sleeping(), 0);
+}
+
+#[cfg(test)]
+#[test]
+fn recorded_frames_read_back() {
+  use crate::{
+    helper::Vector2,
+    record::{Recording, Sample},
+  };
+  let mut recording = Recording::new(5, (512.0, 256.0));
+  let samples = [(3, 10.25, 255.5, 8.0), (0, -40.0, 300.0, 4.5)];
+  let frame = samples.iter().map(|&(index, x, y, radius)| Sample {
+    index,
+    position: Vector2::new(x, y),
+    radius,
+    rested: 1.5,
+  });
+  recording.push(frame, true, 0.0).unwrap();
+  recording.push(std::iter::empty(), false, 0.25).unwrap();
+  let (first, obstacles, _) = recording.frame(0).unwrap();
+  assert!(obstacles);
+  for (read, &(index, x, y, radius)) in first.iter().zip(&samples) {
+    assert_eq!(read.index, index);
+    assert!((read.position.x - x).abs() < 0.02);
+    assert!((read.position.y - y).abs() < 0.02);
+    assert!((read.radius - radius).abs() < 0.01);
+    assert_eq!(read.rested, 1.5);
+  }
+  assert_eq!(recording.frame(1).unwrap().0.len(), 0);
+}
+
+#[cfg(test)]
+#[test]
+fn sampling_ignores_stray_pixels() {
+  use crate::color::{Sampler, Sampling};
+  let mut image = image::RgbImage::from_pixel(21, 21, image::Rgb([200; 3]));
+  image.put_pixel(10, 10, image::Rgb([0; 3]));
+  let sample = |sampling, linear| {
+    Sampler::new(&image, sampling, linear)
+      .sample((10.0, 10.0), (6.0, 6.0))
+      .0
+  };
+  assert_eq!(sample(Sampling::Center, false), 0);
+  assert_eq!(sample(Sampling::Median, false), 200);
+  assert!(sample(Sampling::DiskAverage, false) > 190);
+  assert!(
+    sample(Sampling::DiskAverage, true) > sample(Sampling::DiskAverage, false)
+  );
+  assert!(
+    sample(Sampling::Gaussian, false) < sample(Sampling::DiskAverage, false)
+  );
+}
+
+#[cfg(test)]
+#[test]
+fn palettes_load_and_snap() {
+  use crate::{helper::Color, palette::Palette};
+  let path = std::env::temp_dir().join("fishbowl_palette_test.gpl");
+  std::fs::write(
+    &path,
+    "GIMP Palette\nName: Test\nColumns: 2\n# comment\n255 0 0 Red\n  0 0 \
+     255\tBlue\n",
+  )
+  .unwrap();
+  let palette = Palette::load(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+  assert_eq!(palette.colors, vec![Color(255, 0, 0), Color(0, 0, 255)]);
+  let mut colors = vec![Color(200, 40, 60), Color(30, 20, 140)];
+  palette.snap(&mut colors);
+  assert_eq!(colors, palette.colors);
+  let image = [Color(250, 0, 0), Color(240, 10, 0), Color(0, 0, 250)];
+  for palette in [Palette::median_cut(&image, 2), Palette::kmeans(&image, 2)] {
+    assert_eq!(palette.colors.len(), 2);
+    assert_eq!(palette.nearest(Color(0, 0, 200)).2, 250);
+  }
+}
+
+#[cfg(test)]
+#[test]
+fn dithering_keeps_average_tone() {
+  use crate::{
+    dither::dither,
+    helper::{Color, Vector2},
+    palette::Palette,
+  };
+  let palette = Palette::new(vec![Color(0, 0, 0), Color(255, 255, 255)]);
+  let sites: Vec<_> = (0..400)
+    .map(|i| {
+      let (x, y) = ((i % 20) as f32, (i / 20) as f32);
+      // Offset rows, like packed circles
+      (
+        i,
+        Vector2::new(x * 10.0 + y % 2.0 * 5.0, y * 8.7 + 5.0),
+        5.0,
+      )
+    })
+    .collect();
+  let mut colors = vec![Color(128, 128, 128); sites.len()];
+  dither(&palette, &mut colors, &sites, (210.0, 180.0), 1.0);
+  let white = colors.iter().filter(|c| c.0 == 255).count();
+  // The error is spread in sRGB, so half gray is about
+  // half white
+  assert!((180..=220).contains(&white), "{} white", white);
+}
+
+#[cfg(test)]
+#[test]
+fn wave_reveals_from_the_top() {
+  use crate::{
+    helper::Color,
+    transition::{Moment, Reveal, StartColor, Transition},
+  };
+  let transition = Transition {
+    start: "#000000".parse::<StartColor>().unwrap(),
+    reveal: "wave:1".parse::<Reveal>().unwrap(),
+  };
+  let target = Color(200, 100, 50);
+  let at = |height: f32, wave: Option<f32>| {
+    transition.color(
+      target,
+      &Moment {
+        index: 0,
+        count: 1,
+        born: 0.0,
+        rested: 0.0,
+        height,
+        time: 5.0,
+        wave,
+      },
+    )
+  };
+  assert_eq!(at(0.0, None), Color(0, 0, 0));
+  // Halfway through, the top is done and the bottom hasn't
+  // started
+  assert_eq!(at(0.1, Some(0.5)), target);
+  assert_eq!(at(0.9, Some(0.5)), Color(0, 0, 0));
+  let seconds = transition.wave_seconds().unwrap();
+  assert_eq!(at(1.0, Some(seconds)), target);
+  assert!("wave:0".parse::<Reveal>().is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn drained_circles_come_back() {
+  use crate::{sim::Simulation, timeline::Event};
+  let mut sim = Simulation::new(96.0, 96.0, 3.0, 11);
+  pollster::block_on(sim.steps(600));
+  let count = sim.circles();
+  let mut before: Vec<usize> = sim.circles.iter().map(|c| c.index()).collect();
+  sim.apply(Event::OpenFloor);
+  pollster::block_on(sim.steps(300));
+  assert!(sim.circles() < count);
+  sim.apply(Event::Refill);
+  pollster::block_on(sim.steps(900));
+  let mut after: Vec<usize> = sim.circles.iter().map(|c| c.index()).collect();
+  before.sort();
+  after.sort();
+  assert_eq!(before, after);
+}
+
+#[cfg(test)]
+#[test]
+fn output_size_follows_aspect() {
+  use crate::{output_size, Aspect};
+  let ratio = |spec: &str| match spec.parse::<Aspect>().unwrap() {
+    Aspect::Ratio(ratio) => ratio,
+    Aspect::Auto => panic!("not a ratio"),
+  };
+  assert_eq!(output_size(None, None, None), Ok((512, 512)));
+  assert_eq!(output_size(None, None, Some(ratio("16:9"))), Ok((512, 288)));
+  assert_eq!(
+    output_size(None, Some(300), Some(ratio("0.5"))),
+    Ok((150, 300))
+  );
+  assert_eq!(output_size(Some(333), Some(201), None), Ok((333, 201)));
+  assert!(output_size(Some(1), Some(1), Some(1.0)).is_err());
+  assert!(output_size(Some(0), None, None).is_err());
+  assert!("4:0".parse::<Aspect>().is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn placement_letterboxes_and_crops() {
+  use crate::{
+    color::{parse_align, Fit, Placement},
+    helper::Vector2,
+  };
+  let bounds = (Vector2::new(0.0, 0.0), Vector2::new(200.0, 100.0));
+  // A square image in a wide bowl
+  let contain = Placement::new(Fit::Contain, (0.5, 0.5), bounds, (64, 64));
+  assert!(contain.coords(Vector2::new(10.0, 50.0)).is_none());
+  assert_eq!(contain.coords(Vector2::new(100.0, 50.0)), Some((0.5, 0.5)));
+  assert_eq!(contain.scale((64, 64)), (0.64, 0.64));
+  let cover = Placement::new(Fit::Cover, (0.5, 0.0), bounds, (64, 64));
+  // Only the top half of the image is kept
+  assert_eq!(cover.coords(Vector2::new(0.0, 0.0)), Some((0.0, 0.0)));
+  assert_eq!(cover.coords(Vector2::new(200.0, 100.0)), Some((1.0, 0.5)));
+  assert!(parse_align("0.5,2").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn fades_stay_opaque() {
+  use crate::{blend, darken, Frames, LOOP_BLEND};
+  let pixel = [200, 100, 50, 255];
+  assert_eq!(darken(&pixel, 0.0), [0, 0, 0, 255]);
+  assert_eq!(darken(&pixel, 0.5), [100, 50, 25, 255]);
+  assert_eq!(darken(&pixel, 1.0), pixel);
+  let mut frames = Frames {
+    size: (1, 1),
+    ..Default::default()
+  };
+  frames.push(vec![255, 0, 0, 255]);
+  frames.push(vec![0, 0, 255, 255]);
+  frames.blend_back();
+  assert_eq!(frames.frames.len(), 2 + LOOP_BLEND);
+  // The last blended frame is nearly back at the first
+  let t = LOOP_BLEND as f32 / (LOOP_BLEND + 1) as f32;
+  assert_eq!(frames.last, blend(&[0, 0, 255, 255], &[255, 0, 0, 255], t));
+  assert_eq!(frames.last, [227, 0, 28, 255]);
+}
+
+#[cfg(test)]
+#[test]
+fn outros_run_their_course() {
+  use crate::{outro::Outro, sim::Simulation};
+  let mut settled = Simulation::new(96.0, 96.0, 3.0, 11);
+  pollster::block_on(settled.steps(600));
+  let count = settled.circles();
+  for spec in ["hold", "fade"] {
+    let outro = spec.parse::<Outro>().unwrap();
+    let mut sim = settled.clone();
+    outro.start(&mut sim);
+    pollster::block_on(sim.steps(300));
+    assert_eq!(sim.circles(), count, "{}", spec);
+    assert!(!outro.done(&sim));
+  }
+  for spec in ["drain", "explode"] {
+    let outro = spec.parse::<Outro>().unwrap();
+    let mut sim = settled.clone();
+    outro.start(&mut sim);
+    assert!(!outro.done(&sim));
+    pollster::block_on(sim.steps(1500));
+    assert!(outro.done(&sim), "{} left {} circles", spec, sim.circles());
+  }
+  assert!("fade:0".parse::<Outro>().is_err());
+  assert!("explode:1,2,3".parse::<Outro>().is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn reverse_plays_frames_backwards() {
+  use crate::{Direction, Frames};
+  let pixels = |frames: &Frames| {
+    frames
+      .frames
+      .iter()
+      .map(|f| (f.palette.clone(), f.buffer.to_vec()))
+      .collect::<Vec<_>>()
+  };
+  let mut frames = Frames {
+    size: (1, 1),
+    ..Default::default()
+  };
+  for shade in [0, 100, 200] {
+    frames.push(vec![shade, 50, 50, 255]);
+  }
+  let mut forward = pixels(&frames);
+  frames.arrange(Direction::Reverse);
+  forward.reverse();
+  assert_eq!(pixels(&frames), forward);
+  // Looping blends back to what is now the first frame
+  assert_eq!(frames.first, Some(vec![200, 50, 50, 255]));
+  assert_eq!(frames.last, [0, 50, 50, 255]);
+}
+
+#[cfg(test)]
+#[test]
+fn radius_map_sizes_circles() {
+  use crate::size::SizeField;
+  // Small circles on the left, big ones on the right
+  let config = small_config()
+    .radius_range(2.0, 5.0)
+    .size_field(SizeField::new(2, 1, vec![0.0, 1.0]))
+    .build()
+    .unwrap();
+  let (sim, _) = replay(&config, vec![gray_image()]);
+  let mean = |left: bool| {
+    let radii: Vec<f32> = sim
+      .circles
+      .iter()
+      .filter(|c| (c.position.x < 48.0) == left)
+      .map(|c| c.radius)
+      .collect();
+    radii.iter().sum::<f32>() / radii.len() as f32
+  };
+  assert!(mean(true) < 3.0, "left mean {}", mean(true));
+  assert!(mean(false) > 4.0, "right mean {}", mean(false));
+}
+
+#[cfg(test)]
+#[test]
+fn detail_shrinks_circles() {
+  use crate::size::SizeField;
+  // Stripes on the left, flat on the right
+  let image = image::RgbImage::from_fn(64, 32, |x, _| {
+    image::Rgb([if x < 32 && x % 4 < 2 { 0 } else { 200 }; 3])
+  });
+  let field = SizeField::from_detail(&image);
+  assert!(field.radius(2.0, 6.0, 0.2, 0.5) < 3.0);
+  assert!(field.radius(2.0, 6.0, 0.8, 0.5) > 5.0);
+}
+
+#[cfg(test)]
+#[test]
+fn size_passes_stop() {
+  use crate::size::SizePasses;
+  let run = |changes: &[f32]| {
+    let mut passes = SizePasses::new();
+    for (i, change) in changes.iter().enumerate() {
+      if !passes.offer(i, *change) {
+        break;
+      }
+    }
+    (passes.passes(), passes.best().unwrap())
+  };
+  // Settled straight away
+  assert_eq!(run(&[0.01, 0.005]), (1, 0));
+  // Stops getting better, keeping the best pass
+  assert_eq!(run(&[0.5, 0.3, 0.29, 0.1]), (3, 1));
+  assert_eq!(run(&[0.5, 0.3, 0.4, 0.1]), (3, 1));
+  // Never more than the most passes
+  assert_eq!(
+    run(&[0.9, 0.7, 0.5, 0.3, 0.2, 0.1]),
+    (SizePasses::<usize>::MAX, 3)
+  );
+}
+
+#[cfg(test)]
+#[test]
+fn gravity_follows_keys_and_shakes() {
+  use crate::gravity::{GravityKey, GravityTrack, Shake};
+  let close = |a: crate::helper::Vector2, (x, y): (f32, f32)| {
+    (a.x - x).abs() < 1e-3 && (a.y - y).abs() < 1e-3
+  };
+  let mut track = GravityTrack::default();
+  let down = track.at(3.0, 100.0);
+  assert_eq!((down.x, down.y), (0.0, 100.0));
+  track.keys = ["2:0,2", "0:90"]
+    .iter()
+    .map(|s| s.parse::<GravityKey>().unwrap())
+    .collect();
+  track.sort();
+  assert!(close(track.at(-1.0, 100.0), (0.0, 100.0)));
+  // Halfway through turning from down to the right while
+  // doubling
+  let half = 150.0 * std::f32::consts::FRAC_1_SQRT_2;
+  assert!(close(track.at(1.0, 100.0), (half, half)));
+  assert!(close(track.at(5.0, 100.0), (200.0, 0.0)));
+  assert_eq!(track.end(), 2.0);
+  track.keys.clear();
+  track.shakes = vec!["1:1,0.5,1".parse::<Shake>().unwrap()];
+  // A quarter of the way through the shake, the swing is
+  // at its peak and the envelope at sin(45°)
+  let peak = 50.0 * std::f32::consts::FRAC_1_SQRT_2;
+  assert!(close(track.at(1.25, 100.0), (peak, 100.0)));
+  // Nothing outside of it
+  assert!(close(track.at(0.5, 100.0), (0.0, 100.0)));
+  assert!(close(track.at(2.5, 100.0), (0.0, 100.0)));
+  assert_eq!(track.end(), 2.0);
+  assert!("1".parse::<Shake>().is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn fields_push_the_right_way() {
+  use crate::{field::parse_field, helper::Vector2};
+  let force = |spec: &str, (x, y): (f32, f32)| {
+    let field = parse_field(spec).unwrap();
+    field.validate().unwrap();
+    let (force, drag) = field.force((100.0, 100.0), 100.0, Vector2::new(x, y));
+    ((force.x.round(), force.y.round()), drag)
+  };
+  let right = (75.0, 50.0);
+  assert_eq!(force("attract", right), ((-100.0, 0.0), 0.0));
+  assert_eq!(force("attract:reach=0.5", right), ((-50.0, 0.0), 0.0));
+  assert_eq!(force("repel:strength=2", right), ((200.0, 0.0), 0.0));
+  // Clockwise, so down on the right
+  assert_eq!(force("vortex", right), ((0.0, 100.0), 0.0));
+  let wind = "wind:angle=0,x1=0,y1=0,x2=0.5,y2=1";
+  assert_eq!(force(wind, (25.0, 50.0)), ((100.0, 0.0), 0.0));
+  assert_eq!(force(wind, right), ((0.0, 0.0), 0.0));
+  assert_eq!(force("drag:strength=2", right), ((0.0, 0.0), 2.0));
+  let mut field = parse_field("wind:start=1,end=2").unwrap();
+  assert!(!field.active(0.5));
+  assert!(field.active(1.5));
+  assert!(!field.active(2.0));
+  field.enabled = false;
+  assert!(!field.active(1.5));
+  assert!(parse_field("wind:start=2,end=1")
+    .unwrap()
+    .validate()
+    .is_err());
+  assert!(parse_field("wind:x1=0").is_err());
+  assert!(parse_field("gust").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn containers_push_circles_back_inside() {
+  use crate::{container::Container, helper::Vector2};
+  let area = (100.0, 100.0);
+  let bowl: Container = "circle".parse().unwrap();
+  // Straight back in along the normal
+  let p = bowl.constrain(area, Vector2::new(95.0, 50.0), 10.0);
+  assert!((p.x - 90.0).abs() < 1e-4 && p.y == 50.0);
+  let p = bowl.constrain(area, Vector2::new(50.0, 120.0), 10.0);
+  assert!(p.x == 50.0 && (p.y - 90.0).abs() < 1e-4);
+  // Inside and clear of the edge stays put
+  let p = bowl.constrain(area, Vector2::new(40.0, 60.0), 10.0);
+  assert_eq!((p.x, p.y), (40.0, 60.0));
+  let triangle: Container = "polygon:0,1;0.5,0;1,1".parse().unwrap();
+  let p = triangle.constrain(area, Vector2::new(50.0, 110.0), 5.0);
+  assert!(p.x == 50.0 && (p.y - 95.0).abs() < 1e-4);
+  assert!(triangle.contains(area, p));
+}
+
+#[cfg(test)]
+#[test]
+fn container_samples_match_area() {
+  use crate::{container::Container, helper::Vector2};
+  let area = (120.0, 80.0);
+  for spec in ["rect", "circle", "rounded:0.5", "polygon:0,0;1,0;0.5,1"] {
+    let container: Container = spec.parse().unwrap();
+    let steps = 200;
+    let mut inside = 0;
+    for y in 0..steps {
+      for x in 0..steps {
+        let p = Vector2::new(
+          (x as f32 + 0.5) / steps as f32 * area.0,
+          (y as f32 + 0.5) / steps as f32 * area.1,
+        );
+        inside += container.contains(area, p) as usize;
+      }
+    }
+    let sampled = inside as f32 / (steps * steps) as f32 * area.0 * area.1;
+    let expected = container.area(area);
+    assert!(
+      (sampled - expected).abs() < expected * 0.01,
+      "{}: sampled {}, expected {}",
+      spec,
+      sampled,
+      expected
+    );
+  }
+  for spec in [
+    "circle:0.5,0.5,0",
+    "circle:0.5,0.5,-1",
+    "rounded:0.6",
+    "rounded:-0.1",
+    "polygon:0,0;1,1;0.5,0.5",
+    "polygon:0,0;0,0;1,1",
+  ] {
+    assert!(spec.parse::<Container>().is_err(), "{}", spec);
+  }
+}
+
+#[cfg(test)]
+#[test]
+fn obstacles_keep_circles_out() {
+  use crate::{helper::Vector2, obstacle::Obstacle, sim::Simulation};
+  let area = (100.0, 100.0);
+  let peg: Obstacle = "peg:0.5,0.5,0.1".parse().unwrap();
+  let (p, normal) = peg.push_out(area, Vector2::new(55.0, 50.0), 8.0).unwrap();
+  assert!((p.x - 68.0).abs() < 1e-4 && p.y == 50.0);
+  assert_eq!((normal.x, normal.y), (1.0, 0.0));
+  assert!(peg.push_out(area, Vector2::new(70.0, 50.0), 8.0).is_none());
+  let segment: Obstacle = "segment:0.2,0.5,0.8,0.5,0.02".parse().unwrap();
+  let (p, _) = segment
+    .push_out(area, Vector2::new(50.0, 48.0), 4.0)
+    .unwrap();
+  assert!(p.x == 50.0 && (p.y - 45.0).abs() < 1e-4);
+  let shelf: Obstacle = "box:0.2,0.6,0.8,0.7".parse().unwrap();
+  let mut sim = Simulation::new(96.0, 96.0, 3.0, 11);
+  sim.set_obstacles(vec![shelf.clone()], None);
+  pollster::block_on(sim.steps(900));
+  let area = (96.0, 96.0);
+  for c in &sim.circles {
+    let (d, _) = shelf.distance(area, c.position);
+    assert!(
+      d > c.radius * 0.5,
+      "circle {} is inside the shelf",
+      c.index()
+    );
+  }
+}
+
+#[cfg(test)]
+#[test]
+fn obstacles_are_removed_before_settling() {
+  let config = small_config()
+    .obstacles(vec!["box:0.2,0.6,0.8,0.7".parse().unwrap()], Some(50.0))
+    .build()
+    .unwrap();
+  let (sim, _) = replay(&config, vec![gray_image()]);
+  assert!(!sim.obstacles_active());
+  // Circles fell into where the shelf was
+  let shelf = |y: f32| (57.6..67.2).contains(&y);
+  assert!(sim.circles.iter().filter(|c| shelf(c.position.y)).count() > 20);
+}
+
+#[cfg(test)]
+#[test]
+fn invalid_settings_are_rejected() {
+  use crate::{obstacle::Obstacle, sim::SimulationConfig};
+  let defaults = SimulationConfig::default;
+  assert_eq!(defaults().validate(), Ok(()));
+  let cases = [
+    (
+      "size",
+      SimulationConfig {
+        width: 0.0,
+        ..defaults()
+      },
+    ),
+    (
+      "radius",
+      SimulationConfig {
+        circle_radius: 0.5,
+        ..defaults()
+      },
+    ),
+    (
+      "radius",
+      SimulationConfig {
+        radius_range: (4.0, 51.0),
+        ..defaults()
+      },
+    ),
+    (
+      "Minimum radius",
+      SimulationConfig {
+        radius_range: (8.0, 4.0),
+        ..defaults()
+      },
+    ),
+    (
+      "Thread",
+      SimulationConfig {
+        threads: 0,
+        ..defaults()
+      },
+    ),
+    (
+      "timescale",
+      SimulationConfig {
+        timescale: f32::NAN,
+        ..defaults()
+      },
+    ),
+    (
+      "substep",
+      SimulationConfig {
+        substeps: 65,
+        ..defaults()
+      },
+    ),
+    (
+      "gravity",
+      SimulationConfig {
+        gravity: Some(f32::INFINITY),
+        ..defaults()
+      },
+    ),
+    (
+      "response",
+      SimulationConfig {
+        response_mod: 0.0,
+        ..defaults()
+      },
+    ),
+    (
+      "variance",
+      SimulationConfig {
+        radius_variance: 0.6,
+        ..defaults()
+      },
+    ),
+    (
+      "packing",
+      SimulationConfig {
+        packing: 1.5,
+        ..defaults()
+      },
+    ),
+    (
+      "dither",
+      SimulationConfig {
+        dither: -0.1,
+        ..defaults()
+      },
+    ),
+    (
+      "recorded",
+      SimulationConfig {
+        record: Some(0),
+        ..defaults()
+      },
+    ),
+    // Obstacles from a scene file aren't parsed from text
+    (
+      "peg",
+      SimulationConfig {
+        obstacles: vec![Obstacle::Peg {
+          center: (0.5, 0.5),
+          radius: -1.0,
+        }],
+        ..defaults()
+      },
+    ),
+    (
+      "remove the obstacles",
+      SimulationConfig {
+        remove_obstacles_at: Some(-1.0),
+        ..defaults()
+      },
+    ),
+  ];
+  for (problem, config) in cases {
+    let error = config.validate().unwrap_err();
+    assert!(error.contains(problem), "{}: {}", problem, error);
+  }
+  for spec in ["peg:0.5,0.5,-1", "segment:0.2,0.2,0.2,0.2", "box:0,0,nan,1"] {
+    assert!(spec.parse::<Obstacle>().is_err(), "{}", spec);
+  }
+}
+
+#[cfg(test)]
+#[test]
+fn emitters_spawn_inside_the_area() {
+  use crate::emitter::{parse_emitter, EmitContext};
+  let specs = [
+    "twin",
+    "spout",
+    "rain",
+    "jets",
+    "fountain",
+    "burst:count=7",
+    "spout:rate=0.25",
+  ];
+  let mut counts = vec![];
+  for spec in specs {
+    let emitter = parse_emitter(spec).unwrap();
+    let mut spawns = vec![];
+    for tick in 0..40 {
+      let ctx = EmitContext {
+        tick,
+        clock: tick * 8,
+        area: (200.0, 100.0),
+        radius: 4.0,
+      };
+      emitter.emit(&ctx, &mut spawns);
+    }
+    for s in &spawns {
+      let p = s.position;
+      assert!(
+        (4.0..=196.0).contains(&p.x) && (4.0..=96.0).contains(&p.y),
+        "{} spawned at {},{}",
+        spec,
+        p.x,
+        p.y
+      );
+      assert!(s.velocity.x.is_finite() && s.velocity.y.is_finite());
+    }
+    counts.push(spawns.len());
+  }
+  // Rates are per step and nozzle, and fractional rates
+  // are spread out
+  assert_eq!(counts, [80, 80, 80, 80, 40, 7, 10]);
+  assert!(parse_emitter("burst").unwrap().finished(1));
+  assert!(!parse_emitter("spout").unwrap().finished(1000));
+  for spec in ["spout:rate=0", "rain:rate=-1", "twin:size=2", "hose"] {
+    assert!(parse_emitter(spec).is_err(), "{}", spec);
+  }
+}
+
+#[cfg(test)]
+#[test]
+fn default_nozzles_fire_like_before() {
+  use crate::emitter::{EmitContext, Emitter, TwinNozzle};
+  let (area, radius) = ((200.0, 100.0), 4.0);
+  for clock in [0, 7, 100, 1234, 65535] {
+    let ctx = EmitContext {
+      tick: clock / 8,
+      clock,
+      area,
+      radius,
+    };
+    let mut spawns = vec![];
+    TwinNozzle::default().emit(&ctx, &mut spawns);
+    // The nozzles the simulation started out with
+    let time = clock as f32 / (10.0 * std::f32::consts::PI);
+    let halfwidth = area.0 / 2.0;
+    let left = ((halfwidth - radius * 2.0) * time.cos().abs()) + radius;
+    let right = ((halfwidth - radius * 2.0) * time.sin().abs()) + radius;
+    let velocity = (time.cos(), time.sin().abs());
+    let expected = [(left, radius), (right + halfwidth, radius)];
+    assert_eq!(spawns.len(), 2);
+    for (spawn, position) in spawns.iter().zip(expected) {
+      assert_eq!((spawn.position.x, spawn.position.y), position);
+      assert_eq!((spawn.velocity.x, spawn.velocity.y), velocity);
+    }
+  }
+}
+
+#[cfg(test)]
+#[test]
+fn stuck_emitters_stop_preprocessing() {
+  use crate::emitter::Spout;
+  let config = small_config()
+    .emitters(vec![Box::new(Spout {
+      rate: 0.0,
+      ..Default::default()
+    })])
+    .build()
+    .unwrap();
+  let (_, _, max_circles) = simulate(&config, vec![gray_image()]);
+  assert_eq!(max_circles, 0);
+}
+
+#[cfg(test)]
+#[test]
+fn seeds_change_the_pattern() {
+  use crate::sim::Simulation;
+  let run = |seed| {
+    let mut sim = Simulation::new(96.0, 96.0, 3.0, seed);
+    pollster::block_on(sim.steps(200));
+    assert_eq!(sim.clock, 200 * sim.substeps);
+    sim.checksum()
+  };
+  assert_eq!(run(11), run(11));
+  // Seeds a step apart used to give the same animation, one
+  // step later
+  assert_ne!(run(11), run(11 + 8));
+  assert_ne!(run(11), run(12));
+  assert_eq!(run(usize::MAX), run(usize::MAX));
+}
+
+#[cfg(test)]
+#[test]
+fn timeline_replays_like_preprocessing() {
+  use crate::{
+    field::parse_field,
+    size::SizeField,
+    timeline::{Cue, Event},
+  };
+  let mut swirl = parse_field("vortex:strength=2").unwrap();
+  swirl.name = Some("swirl".into());
+  swirl.enabled = false;
+  let cue = |at, event| Cue { at, event };
+  let field = || "swirl".to_string();
+  let config = small_config()
+    .fields(vec![swirl])
+    .size_field(SizeField::new(2, 1, vec![0.0, 1.0]))
+    .radius_range(2.0, 4.0)
+    .timeline(vec![
+      cue(1.0, Event::EnableField { field: field() }),
+      cue(2.0, Event::DisableField { field: field() }),
+      cue(
+        3.0,
+        Event::Impulse {
+          speed: 3.0,
+          angle: 270.0,
+          center: None,
+        },
+      ),
+      cue(4.0, Event::OpenFloor),
+      cue(4.5, Event::CloseFloor),
+    ])
+    .build()
+    .unwrap();
+  let (mut sim, it, max_circles) = simulate(&config, vec![gray_image()]);
+  // Some fell out in the first size pass, and those still
+  // have a size in the next
+  for index in 0..max_circles {
+    let radius = sim.spawn_radius(index).unwrap();
+    assert!(radius > 1.0, "circle {} spawns at {}", index, radius);
+  }
+  pollster::block_on(sim.steps((it - sim.clock) / sim.substeps));
+  assert!(sim.circles() < max_circles);
+  assert_eq!(Some(sim.checksum()), sim.expected_checksum());
+}
+
+#[cfg(test)]
+#[test]
+fn verify_checks_every_step() {
+  use crate::sim::Simulation;
+  let config = small_config().threads(2).seed(5).build().unwrap();
+  let (sim, it, _) = simulate(&config, vec![gray_image()]);
+  let steps =
+    pollster::block_on(Simulation::verify(&config, vec![gray_image()]));
+  assert_eq!(steps.unwrap(), it / sim.substeps);
+}
+
+#[cfg(test)]
+#[test]
+fn pictures_change_over() {
+  use crate::{emitter::parse_emitter, helper::Color, slideshow::Change};
+  // A burst is done long before the change, so circles
+  // that drained have to come back without it
+  let config = small_config()
+    .emitters(vec![parse_emitter("burst:count=200,y=0.5").unwrap()])
+    .slideshow(Change::Drain { seconds: 1.0 }, 1.0)
+    .seed(3)
+    .build()
+    .unwrap();
+  let images = vec![solid_image([255, 0, 0]), solid_image([0, 0, 255])];
+  let (sim, max_circles) = replay(&config, images);
+  assert_eq!(sim.expected_checksum(), Some(sim.checksum()));
+  assert_eq!(sim.circles(), max_circles);
+  // Red, then a blend while the bowl changes, then blue
+  let end = sim.time();
+  let colors: Vec<Color> = (0..=100)
+    .map(|i| sim.color_at(0, end * i as f32 / 100.0))
+    .collect();
+  assert_eq!(colors[0], Color(255, 0, 0));
+  assert_eq!(colors[100], Color(0, 0, 255));
+  assert!(colors.iter().any(|c| c.0 > 0 && c.2 > 0));
+  assert!(colors
+    .windows(2)
+    .all(|w| w[1].0 <= w[0].0 && w[1].2 >= w[0].2));
+}
+
+#[cfg(test)]
+#[test]
+fn falling_circles_wake_the_pile() {
+  use crate::{emitter::parse_emitter, sim::Simulation};
+  // Everything but one circle lands at once and goes to
+  // sleep, then the last one drops onto the pile
+  let max = Simulation::new(96.0, 96.0, 3.0, 11).max_circles;
+  let pile = format!("burst:count={},y=0.6,speed=0", max - 1);
+  let drop = "burst:at=900,count=1,y=0.05,angle=90,speed=3";
+  let config = small_config()
+    .emitters(vec![
+      parse_emitter(&pile).unwrap(),
+      parse_emitter(drop).unwrap(),
+    ])
+    .build()
+    .unwrap();
+  let (mut sim, it, _) = simulate(&config, vec![gray_image()]);
+  pollster::block_on(sim.steps(900));
+  let resting = sim.sleeping();
+  assert!(resting > sim.circles() * 9 / 10, "{} asleep", resting);
+  let mut least = resting;
+  for _ in 0..60 {
+    pollster::block_on(sim.steps(1));
+    least = least.min(sim.sleeping());
+  }
+  assert!(least < resting / 2, "{} of {} still asleep", least, resting);
+  pollster::block_on(sim.steps((it - sim.clock) / sim.substeps));
+  assert_eq!(sim.expected_checksum(), Some(sim.checksum()));
+}
+
+#[cfg(test)]
+#[test]
+fn checkpoints_resume_preprocessing() {
+  use crate::{
+    sim::{Preprocessing, Simulation},
+    size::SizeField,
+    slideshow::Change,
+    state,
+  };
+  let config = small_config()
+    .size_field(SizeField::new(2, 1, vec![0.0, 1.0]))
+    .radius_range(2.0, 4.0)
+    .slideshow(Change::default(), 1.0)
+    .record(4)
+    .build()
+    .unwrap();
+  let images = vec![gray_image(), solid_image([0, 0, 255])];
+  let (whole, it, max_circles, recording) =
+    pollster::block_on(Simulation::simulate_images(&config, images.clone()));
+  let mut recording = recording.unwrap();
+  // Stopped and loaded again every few hundred steps, in
+  // every size pass and picture
+  let dir = std::env::temp_dir().join("fishbowl_checkpoint_test");
+  std::fs::create_dir_all(&dir).unwrap();
+  let path = dir.join("progress.json");
+  let mut pre = Preprocessing::new(&config, &images);
+  let mut saves = 0;
+  while !pollster::block_on(pre.run(&config, &images, 300)) {
+    state::save_checkpoint(&path, &mut pre).unwrap();
+    pre = state::load_checkpoint(&path).unwrap();
+    saves += 1;
+  }
+  let (resumed, resumed_it, resumed_max, resumed_recording) =
+    pre.finish(&config);
+  let mut resumed_recording = resumed_recording.unwrap();
+  assert!(saves > 3);
+  assert_eq!((resumed_it, resumed_max), (it, max_circles));
+  assert_eq!(resumed.checksum(), whole.checksum());
+  assert_eq!(resumed.expected_checksum(), whole.expected_checksum());
+  assert_eq!(resumed_recording.len(), recording.len());
+  for frame in [0, recording.len() / 2, recording.len() - 1] {
+    let samples = |recording: &mut crate::record::Recording| {
+      let (samples, ..) = recording.frame(frame).unwrap();
+      samples
+        .iter()
+        .map(|s| (s.index, s.position.x.to_bits(), s.position.y.to_bits()))
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(samples(&mut resumed_recording), samples(&mut recording));
+  }
+  for index in 0..max_circles {
+    assert_eq!(resumed.color_at(index, 0.0), whole.color_at(index, 0.0));
+    let end = whole.time();
+    assert_eq!(resumed.color_at(index, end), whole.color_at(index, end));
+  }
+  drop(resumed_recording);
+  std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+/// Something that happens to the whole simulation at a
+/// point in time
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+  /// Kicks every circle along `angle`, in degrees with 90
+  /// pointing down, or away from `center` when given.
+  /// `speed` is in pixels per substep, like emitters
+  Impulse {
+    speed: f32,
+    #[serde(default)]
+    angle: f32,
+    #[serde(default)]
+    center: Option<(f32, f32)>,
+  },
+  /// Stops holding circles up from below, so they fall out
+  /// of the bowl and are removed
+  OpenFloor,
+  CloseFloor,
+  /// Closes the floor and sends the circles that fell out
+  /// back in through the emitters, in the order they fell.
+  /// Once the emitters are done they are dropped in along
+  /// the top instead
+  Refill,
+  /// Turns on the force field with this `name`
+  EnableField {
+    field: String,
+  },
+  DisableField {
+    field: String,
+  },
+  PauseEmitters,
+  ResumeEmitters,
+}
+
+/// An [`Event`] and the time it happens at
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Cue {
+  /// Seconds since the start
+  pub at: f32,
+  #[serde(flatten)]
+  pub event: Event,
+}
+
+/// Puts cues in time order, keeping the order of cues at
+/// the same time
+pub fn sort(cues: &mut [Cue]) {
+  cues.sort_by(|a, b| a.at.total_cmp(&b.at));
+}
@@ -0,0 +1,158 @@
+use std::str::FromStr;
+
+use crate::{helper::Color, palette::Palette};
+
+// Width of the blend at the front of a wave, as a fraction
+// of the height
+const WAVE_SOFTNESS: f32 = 0.2;
+
+/// Color circles have before they take on their own
+#[derive(Clone, Copy, Debug)]
+pub enum StartColor {
+  White,
+  Gray,
+  /// A different hue for each circle, going round the color
+  /// wheel in spawn order
+  Rainbow,
+  Solid(Color),
+}
+
+/// How circles go from the start color to their own
+#[derive(Clone, Copy, Debug)]
+pub enum Reveal {
+  /// Over `seconds` from when each circle spawns
+  Time { seconds: f32 },
+  /// Over `seconds` spent resting
+  Rest { seconds: f32 },
+  /// Top to bottom over `seconds`, once everything has
+  /// settled
+  Wave { seconds: f32 },
+}
+
+/// Colors circles in while the animation plays
+#[derive(Clone, Copy, Debug)]
+pub struct Transition {
+  pub start: StartColor,
+  pub reveal: Reveal,
+}
+
+/// What is known about a circle on a frame
+pub struct Moment {
+  /// Spawn index, out of `count` circles
+  pub index: usize,
+  pub count: usize,
+  /// Seconds since the start when it spawned
+  pub born: f32,
+  /// Seconds spent resting
+  pub rested: f32,
+  /// Height in the area, 0 at the top and 1 at the bottom
+  pub height: f32,
+  /// Seconds since the start
+  pub time: f32,
+  /// Seconds since the wave started, if it has
+  pub wave: Option<f32>,
+}
+
+impl Transition {
+  /// Seconds the animation has to keep going after
+  /// settling, for the wave to pass
+  pub fn wave_seconds(&self) -> Option<f32> {
+    match self.reveal {
+      Reveal::Wave { seconds } => Some(seconds * (1.0 + WAVE_SOFTNESS)),
+      _ => None,
+    }
+  }
+
+  /// Color of a circle whose own color is `target`
+  pub fn color(&self, target: Color, moment: &Moment) -> Color {
+    let progress = match self.reveal {
+      Reveal::Time { seconds } => (moment.time - moment.born) / seconds,
+      Reveal::Rest { seconds } => moment.rested / seconds,
+      Reveal::Wave { seconds } => match moment.wave {
+        // The front moves past the bottom, so the last
+        // circles blend in fully
+        Some(wave) => {
+          let front = wave / seconds * (1.0 + WAVE_SOFTNESS);
+          (front - moment.height) / WAVE_SOFTNESS
+        },
+        None => 0.0,
+      },
+    };
+    let t = progress.clamp(0.0, 1.0);
+    // Eased so circles don't snap in and out of blending
+    let t = t * t * (3.0 - 2.0 * t);
+    let start = match self.start {
+      StartColor::White => Color(255, 255, 255),
+      StartColor::Gray => Color(128, 128, 128),
+      StartColor::Rainbow => {
+        hue(moment.index as f32 / moment.count.max(1) as f32)
+      },
+      StartColor::Solid(color) => color,
+    };
+    let mix =
+      |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    Color(
+      mix(start.0, target.0),
+      mix(start.1, target.1),
+      mix(start.2, target.2),
+    )
+  }
+}
+
+// Fully saturated color `h` of the way round the color
+// wheel
+fn hue(h: f32) -> Color {
+  let channel = |offset: f32| {
+    let k = (h * 6.0 + offset) % 6.0;
+    let v = 1.0 - (k.min(4.0 - k).clamp(0.0, 1.0));
+    (v * 255.0).round() as u8
+  };
+  Color(channel(5.0), channel(3.0), channel(1.0))
+}
+
+impl FromStr for StartColor {
+  type Err = String;
+
+  /// Parses `white`, `gray`, `rainbow` or a hex color such
+  /// as `#ff8800`
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    match spec {
+      "white" => Ok(Self::White),
+      "gray" | "grey" => Ok(Self::Gray),
+      "rainbow" => Ok(Self::Rainbow),
+      _ => Palette::parse_hex(spec).map(Self::Solid).map_err(|_| {
+        format!(
+          "unknown start color '{}', expected white, gray, rainbow or a hex \
+           color",
+          spec
+        )
+      }),
+    }
+  }
+}
+
+impl FromStr for Reveal {
+  type Err = String;
+
+  /// Parses `time[:seconds]`, `rest[:seconds]` or
+  /// `wave[:seconds]`
+  fn from_str(spec: &str) -> Result<Self, Self::Err> {
+    let (name, seconds) = spec.split_once(':').unwrap_or((spec, ""));
+    let seconds =
+      match seconds.trim() {
+        "" => 2.0,
+        s => s.parse::<f32>().ok().filter(|s| *s > 0.0).ok_or_else(|| {
+          format!("invalid duration '{}', must be above 0", s)
+        })?,
+      };
+    match name {
+      "time" => Ok(Self::Time { seconds }),
+      "rest" => Ok(Self::Rest { seconds }),
+      "wave" => Ok(Self::Wave { seconds }),
+      _ => Err(format!(
+        "unknown reveal '{}', expected one of time, rest or wave",
+        name
+      )),
+    }
+  }
+}